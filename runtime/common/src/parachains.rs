@@ -49,7 +49,7 @@ use primitives::{
 	},
 };
 use frame_support::{
-	Parameter, dispatch::DispatchResult, decl_storage, decl_module, decl_error, ensure,
+	Parameter, dispatch::DispatchResult, decl_storage, decl_module, decl_event, decl_error, ensure,
 	traits::{Currency, Get, WithdrawReason, ExistenceRequirement, Randomness},
 };
 use sp_runtime::{
@@ -59,7 +59,7 @@ use sp_runtime::{
 use inherents::{ProvideInherent, InherentData, MakeFatalError, InherentIdentifier};
 
 use system::{
-	ensure_none, ensure_signed,
+	ensure_none, ensure_root, ensure_signed,
 	offchain::{CreateSignedTransaction, SendSignedTransaction, Signer},
 };
 use crate::attestations::{self, IncludedBlocks};
@@ -221,10 +221,35 @@ impl GetSessionNumber for sp_session::MembershipProof {
 	}
 }
 
+/// A handler for queries of the code of a para at a height for which the code is no
+/// longer held on-chain.
+pub trait OnCodeUnavailable<N> {
+	/// Called with the para and the height at which its code was requested.
+	fn on_code_unavailable(id: ParaId, at: N);
+}
+
+impl<N> OnCodeUnavailable<N> for () {
+	fn on_code_unavailable(_: ParaId, _: N) {}
+}
+
+/// A handler consulted before past code of a para is pruned, which may hold back the pruning.
+pub trait OnBeforeCodePrune<N> {
+	/// Whether the code of the para which was replaced at the given block may be pruned. If
+	/// not, pruning it is retried at the next scan of the pruning queue.
+	fn may_prune(id: ParaId, replaced_at: N) -> bool;
+}
+
+impl<N> OnBeforeCodePrune<N> for () {
+	fn may_prune(_: ParaId, _: N) -> bool { true }
+}
+
 pub trait Trait: CreateSignedTransaction<Call<Self>> + attestations::Trait + session::historical::Trait {
 	// The transaction signing authority
 	type AuthorityId: system::offchain::AppCrypto<Self::Public, Self::Signature>;
 
+	/// The overarching event type.
+	type Event: From<Event<Self>> + Into<<Self as system::Trait>::Event>;
+
 	/// The outer origin type.
 	type Origin: From<Origin> + From<system::RawOrigin<Self::AccountId>>;
 
@@ -271,6 +296,18 @@ pub trait Trait: CreateSignedTransaction<Call<Self>> + attestations::Trait + ses
 	/// can be re-checked.
 	type SlashPeriod: Get<Self::BlockNumber>;
 
+	/// The maximum number of paras whose code can be replaced in a single call to
+	/// `force_set_current_codes`.
+	type MaxForcedCodeUpdates: Get<u32>;
+
+	/// Handler for queries of the code of a para at a height for which the code has
+	/// already been pruned.
+	type OnCodeUnavailable: OnCodeUnavailable<Self::BlockNumber>;
+
+	/// Handler which may hold back the pruning of past code which has exited the slashing
+	/// window.
+	type OnBeforeCodePrune: OnBeforeCodePrune<Self::BlockNumber>;
+
 	/// Proof type.
 	///
 	/// We need this type to bind the `KeyOwnerProofSystem::Proof` to necessary bounds.
@@ -351,6 +388,10 @@ const MAX_QUEUE_COUNT: usize = 100;
 /// further messages may be added to it. If it exceeds this then the queue may contain only a
 /// single message.
 const WATERMARK_QUEUE_SIZE: usize = 20000;
+/// The magic number which all WASM blobs begin with.
+const WASM_MAGIC: [u8; 4] = [0x00, 0x61, 0x73, 0x6d];
+/// The weight charged for each byte of validation code written by a root call.
+const CODE_BYTE_WEIGHT: Weight = 1_000;
 
 /// Metadata used to track previous parachain validation code that we keep in
 /// the state.
@@ -378,6 +419,18 @@ impl<N: Ord + Copy> ParaPastCodeMeta<N> {
 		self.upgrade_times.insert(0, at)
 	}
 
+	// whether the metadata is internally consistent: replacements are ordered from newest
+	// to oldest, and all of them are after the last pruned replacement, if any.
+	fn is_consistent(&self) -> bool {
+		let descending = self.upgrade_times.windows(2).all(|w| w[0] >= w[1]);
+		let after_pruned = match (self.last_pruned, self.upgrade_times.last()) {
+			(Some(last_pruned), Some(&oldest)) => last_pruned < oldest,
+			_ => true,
+		};
+
+		descending && after_pruned
+	}
+
 	// Yields the block number of the code that should be used for validating at
 	// the given block number.
 	//
@@ -430,20 +483,169 @@ impl<N: Ord + Copy> ParaPastCodeMeta<N> {
 	// returns an iterator of block numbers at which code was replaced, where the replaced
 	// code should be now pruned, in ascending order.
 	fn prune_up_to(&'_ mut self, max: N) -> impl Iterator<Item=N> + '_ {
+		self.prune_up_to_bounded(max, usize::max_value())
+	}
+
+	// like `prune_up_to`, but prunes at most `limit` of the oldest entries. pruning
+	// may be resumed by calling this again with the same `max`.
+	fn prune_up_to_bounded(&'_ mut self, max: N, limit: usize) -> impl Iterator<Item=N> + '_ {
+		let len = self.upgrade_times.len();
 		match self.upgrade_times.iter().position(|&t| t <= max) {
 			None => {
 				// this is a no-op `drain` - desired because all
 				// logged code upgrades occurred after `max`.
-				self.upgrade_times.drain(self.upgrade_times.len()..).rev()
+				self.upgrade_times.drain(len..).rev()
 			}
 			Some(pos) => {
-				self.last_pruned = Some(self.upgrade_times[pos]);
+				let pos = pos.max(len.saturating_sub(limit));
+				if let Some(&newest_pruned) = self.upgrade_times.get(pos) {
+					self.last_pruned = Some(newest_pruned);
+				}
 				self.upgrade_times.drain(pos..).rev()
 			}
 		}
 	}
 }
 
+/// All validation code the relay chain holds for a single para, bundled for archival or
+/// migration.
+#[derive(Encode, Decode, Clone, PartialEq, Eq, RuntimeDebug)]
+pub struct ParaCodeExport<N> {
+	/// The code currently used to validate the para, if any.
+	pub current: Option<ValidationCode>,
+	/// Replaced code which is still retained, along with the block number at which it was
+	/// replaced. Ordered by ascending block number.
+	pub past: Vec<(N, ValidationCode)>,
+	/// Code which is planned to replace the current code, along with the block number at
+	/// which the replacement is expected.
+	pub future: Option<(N, ValidationCode)>,
+	/// The block number at which the most recently pruned code was replaced, if any. Code
+	/// is not resolved for blocks up to this one.
+	pub last_pruned: Option<N>,
+}
+
+impl<N> ParaCodeExport<N> {
+	/// All of the code in the export: the current code, past code and planned code.
+	pub fn codes(&self) -> impl Iterator<Item=&ValidationCode> + '_ {
+		self.current.iter()
+			.chain(self.past.iter().map(|(_, code)| code))
+			.chain(self.future.iter().map(|(_, code)| code))
+	}
+}
+
+/// The parameters which currently apply to a code upgrade of a para.
+#[derive(Encode, Decode, Clone, PartialEq, Eq, RuntimeDebug)]
+pub struct UpgradeParameters<N> {
+	/// The number of blocks between a code upgrade being signalled and it being applied.
+	pub min_delay: N,
+	/// The maximum size of new validation code, in bytes.
+	pub max_code_size: u32,
+	/// The number of blocks until a candidate of the para may signal a code upgrade.
+	pub cooldown_remaining: N,
+	/// Whether planned code upgrades of the para are held back by governance.
+	pub locked: bool,
+}
+
+/// The number of entries in each of the storage maps holding the code and heads of paras.
+#[cfg(feature = "std")]
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct EntryCounts {
+	/// Entries in `Code`.
+	pub current_code: u32,
+	/// Entries in `PastCode`.
+	pub past_code: u32,
+	/// Entries in `FutureCode`.
+	pub future_code: u32,
+	/// Entries in `Heads`.
+	pub heads: u32,
+	/// Entries in `PastCodeMeta`.
+	pub past_code_meta: u32,
+}
+
+/// A summary of the storage of paras at a point in time, for debugging state transitions.
+#[cfg(feature = "std")]
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ParasSnapshot<Hash> {
+	/// The number of entries in each of the storage maps.
+	pub counts: EntryCounts,
+	/// The hash of the current code of each para.
+	pub code: std::collections::BTreeMap<ParaId, Hash>,
+	/// The hash of the head of each para.
+	pub heads: std::collections::BTreeMap<ParaId, Hash>,
+	/// The hash of the planned code of each para.
+	pub future_code: std::collections::BTreeMap<ParaId, Hash>,
+}
+
+/// A difference between two snapshots of the storage of paras.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StateChange {
+	/// The current code of a para was added, removed or replaced.
+	Code(ParaId),
+	/// The head of a para was added, removed or replaced.
+	Head(ParaId),
+	/// The planned code of a para was added, removed or replaced.
+	FutureCode(ParaId),
+	/// The number of entries in the named storage map changed from the first to the second.
+	EntryCount(&'static str, u32, u32),
+}
+
+#[cfg(feature = "std")]
+impl<Hash: PartialEq> ParasSnapshot<Hash> {
+	/// Describe what changed between two snapshots: first any changed entries, ordered by
+	/// map and then by `ParaId`, then any changed entry counts.
+	pub fn diff(old: &Self, new: &Self) -> Vec<StateChange> {
+		use std::collections::{BTreeMap, BTreeSet};
+
+		fn changed_paras<Hash: PartialEq>(
+			old: &BTreeMap<ParaId, Hash>,
+			new: &BTreeMap<ParaId, Hash>,
+		) -> impl Iterator<Item = ParaId> {
+			old.keys().chain(new.keys())
+				.filter(|id| old.get(*id) != new.get(*id))
+				.cloned()
+				.collect::<BTreeSet<_>>()
+				.into_iter()
+		}
+
+		let mut changes: Vec<_> = changed_paras(&old.code, &new.code).map(StateChange::Code)
+			.chain(changed_paras(&old.heads, &new.heads).map(StateChange::Head))
+			.chain(changed_paras(&old.future_code, &new.future_code).map(StateChange::FutureCode))
+			.collect();
+
+		let counts = [
+			("current_code", old.counts.current_code, new.counts.current_code),
+			("past_code", old.counts.past_code, new.counts.past_code),
+			("future_code", old.counts.future_code, new.counts.future_code),
+			("heads", old.counts.heads, new.counts.heads),
+			("past_code_meta", old.counts.past_code_meta, new.counts.past_code_meta),
+		];
+		changes.extend(counts.iter()
+			.filter(|&&(_, from, to)| from != to)
+			.map(|&(name, from, to)| StateChange::EntryCount(name, from, to))
+		);
+
+		changes
+	}
+}
+
+/// The version of the storage of the module, used to run each storage migration once.
+#[derive(Encode, Decode, Clone, Copy, PartialEq, Eq, RuntimeDebug)]
+pub enum Releases {
+	/// The storage predating `TotalHeadBytes`.
+	V0,
+	/// `TotalHeadBytes` is tracked.
+	V1,
+	/// `RetainedPastCodeSize` is tracked.
+	V2,
+}
+
+impl Default for Releases {
+	fn default() -> Self {
+		Releases::V0
+	}
+}
+
 decl_storage! {
 	trait Store for Module<T: Trait> as Parachains
 	{
@@ -460,15 +662,52 @@ decl_storage! {
 		PastCode: map hasher(twox_64_concat) (ParaId, T::BlockNumber) => Option<ValidationCode>;
 		/// Past code pruning, in order of priority.
 		PastCodePruning get(fn past_code_pruning_tasks): Vec<(ParaId, T::BlockNumber)>;
+		/// Code which governance has set to be used in place of the code of a para at a
+		/// particular block, e.g. to fix the validation of that block in an emergency. These
+		/// are removed along with the para, and are otherwise kept until cleared by governance.
+		CodeSubstitutes get(fn code_substitute):
+			double_map hasher(twox_64_concat) ParaId, hasher(twox_64_concat) T::BlockNumber => Option<ValidationCode>;
+		/// The total size in bytes of the past code which is awaiting pruning.
+		RetainedPastCodeSize get(fn retention_pressure): u64;
+		/// The number of blocks between scans of the past code pruning queue. Past code may
+		/// linger for less than this many blocks beyond the end of its slashing window.
+		/// Zero or one means the queue is scanned in every block.
+		pub PruningCheckInterval get(fn pruning_check_interval): T::BlockNumber;
+		/// Whether new validation code is required to be a WASM blob.
+		pub RequireWasmMagic get(fn require_wasm_magic): bool;
+		/// Hashes of validation code which has been vetted by governance.
+		pub CodeHashAllowlist get(fn code_hash_allowlist): Vec<T::Hash>;
+		/// Whether code upgrades are required to be to code in the `CodeHashAllowlist`.
+		pub EnforceCodeAllowlist get(fn enforce_code_allowlist): bool;
+		/// Hashes of validation code which has been found to produce invalid blocks, and which
+		/// must never be upgraded to again.
+		pub RejectedCodeHashes get(fn code_hash_rejected): map hasher(twox_64_concat) T::Hash => bool;
 		// The block number at which the planned code change is expected for a para.
 		// The change will be applied after the first parablock for this ID included which executes
 		// in the context of a relay chain block with a number >= `expected_at`.
 		FutureCodeUpgrades get(fn code_upgrade_schedule): map hasher(twox_64_concat) ParaId => Option<T::BlockNumber>;
 		// The actual future code of a para.
 		FutureCode: map hasher(twox_64_concat) ParaId => ValidationCode;
+		/// The code of paras which are paused. This is held aside, rather than archived as
+		/// past code, so that the para can be resumed without re-uploading its code.
+		PausedCode get(fn paused_code): map hasher(twox_64_concat) ParaId => Option<ValidationCode>;
+		/// Paras whose planned code upgrades are held back until unlocked by governance.
+		CodeUpgradeLocked get(fn code_upgrade_locked): map hasher(twox_64_concat) ParaId => bool;
+		/// The number of code upgrades scheduled for a para. Used to reject stale requests
+		/// to schedule an upgrade.
+		UpgradeNonce get(fn upgrade_nonce): map hasher(twox_64_concat) ParaId => u32;
+		/// The block at which a para was registered. Paras registered at genesis are registered
+		/// at block zero.
+		pub RegisteredAt: map hasher(twox_64_concat) ParaId => T::BlockNumber;
+		/// The number of code upgrades applied to a para since it was registered.
+		UpgradeCount get(fn upgrade_count): map hasher(twox_64_concat) ParaId => u32;
 
 		/// The heads of the parachains registered at present.
 		pub Heads get(fn parachain_head): map hasher(twox_64_concat) ParaId => Option<HeadData>;
+		/// The total size in bytes of the heads of all paras.
+		pub TotalHeadBytes get(fn total_head_bytes): u64;
+		/// The version of the storage of the module. New chains start at the latest version.
+		StorageVersion: Releases;
 		/// Messages ready to be dispatched onto the relay chain. It is subject to
 		/// `MAX_MESSAGE_COUNT` and `WATERMARK_MESSAGE_SIZE`.
 		pub RelayDispatchQueue: map hasher(twox_64_concat) ParaId => Vec<UpwardMessage>;
@@ -484,10 +723,46 @@ decl_storage! {
 		///
 		/// `None` if not yet updated.
 		pub DidUpdate: Option<Vec<ParaId>>;
+
+		/// The paras whose heads were changed in this block, ordered by ParaId. Cleared at the
+		/// start of each block.
+		HeadsChangedThisBlock get(fn heads_changed_this_block): Vec<ParaId>;
 	}
 	add_extra_genesis {
 		config(authorities): Vec<ValidatorId>;
-		build(|config| Module::<T>::initialize_authorities(&config.authorities))
+		build(|config| {
+			assert!(
+				Module::<T>::upgrade_delay_within_slash_period(),
+				"`ValidationUpgradeDelay` must not exceed `SlashPeriod`",
+			);
+			<Module<T> as Store>::StorageVersion::put(Releases::V2);
+			Module::<T>::initialize_authorities(&config.authorities)
+		})
+	}
+}
+
+decl_event! {
+	pub enum Event<T> where
+		Hash = <T as system::Trait>::Hash,
+		BlockNumber = <T as system::Trait>::BlockNumber,
+	{
+		/// A para was offboarded. The hash of the head it had at the time is supplied, as the
+		/// head itself is removed from the state.
+		ParaOffboardedWithHead(ParaId, Hash),
+		/// A planned code upgrade was discarded as the para was offboarded before it was
+		/// applied. The hash of the discarded code is supplied.
+		UpgradeDiscardedOnOffboard(ParaId, Hash),
+		/// A code upgrade of a para was scheduled. The block at which it is expected to be
+		/// applied and the hash of the new code are supplied.
+		CodeUpgradeScheduled(ParaId, BlockNumber, Hash),
+		/// A planned code upgrade of a para was discarded when due, as the new code exceeds
+		/// the maximum code size or has been marked as invalid.
+		UpgradeRejectedAtApplication(ParaId),
+		/// A new head of a para was accepted. The hash of the head and the number of the
+		/// relay-chain block in whose context it was produced are supplied.
+		HeadAccepted(ParaId, Hash, BlockNumber),
+		/// A planned code upgrade of a para was cancelled by governance.
+		UpgradeForciblyCancelled(ParaId),
 	}
 }
 
@@ -531,6 +806,35 @@ decl_error! {
 		CannotPayFees,
 		/// Unexpected relay-parent for a candidate receipt.
 		UnexpectedRelayParent,
+		/// The para already has code or a code history.
+		ParaCodeAlreadyExists,
+		/// The para is not paused.
+		ParaNotPaused,
+		/// The execution context is in the future.
+		ExecutionContextInFuture,
+		/// The execution context is before the most recent code change of the para.
+		ExecutionContextOutdated,
+		/// The validation code is not a WASM blob.
+		NotWasm,
+		/// The upgrade nonce supplied does not match the current one for the para.
+		StaleUpgrade,
+		/// The para has no planned code upgrade.
+		NoPlannedUpgrade,
+		/// Too many code updates were supplied at once.
+		TooManyCodeUpdates,
+		/// The hash of the validation code is not in the allowlist.
+		CodeNotAllowed,
+		/// The new validation code is the same as the current code of the para.
+		NoOpUpgrade,
+		/// The validation code has been marked as invalid.
+		CodeRejected,
+		/// The same para was supplied more than once.
+		DuplicateParaId,
+		/// The past code of an export is not in strictly ascending order of replacement, or
+		/// is not after the most recently pruned code.
+		PastCodeOutOfOrder,
+		/// The planned upgrade of an export is not expected after its most recent code change.
+		FutureCodeOutOfOrder,
 	}
 }
 
@@ -539,8 +843,29 @@ decl_module! {
 	pub struct Module<T: Trait> for enum Call where origin: <T as system::Trait>::Origin {
 		type Error = Error<T>;
 
+		fn deposit_event() = default;
+
+		fn on_runtime_upgrade() -> Weight {
+			let mut weight = T::DbWeight::get().reads(1);
+
+			if <Self as Store>::StorageVersion::get() == Releases::V0 {
+				weight += Self::migrate_total_head_bytes();
+				<Self as Store>::StorageVersion::put(Releases::V1);
+				weight += T::DbWeight::get().writes(1);
+			}
+
+			if <Self as Store>::StorageVersion::get() == Releases::V1 {
+				weight += Self::migrate_retained_past_code_size();
+				<Self as Store>::StorageVersion::put(Releases::V2);
+				weight += T::DbWeight::get().writes(1);
+			}
+
+			weight
+		}
+
 		fn on_initialize(now: T::BlockNumber) -> Weight {
 			<Self as Store>::DidUpdate::kill();
+			<Self as Store>::HeadsChangedThisBlock::kill();
 
 			Self::do_old_code_pruning(now);
 
@@ -668,6 +993,377 @@ decl_module! {
 
 			Ok(())
 		}
+
+		/// Set the number of blocks between scans of the past code pruning queue.
+		///
+		/// Must be called from Root origin.
+		#[weight = (10_000_000, DispatchClass::Operational)]
+		pub fn set_pruning_check_interval(origin, interval: T::BlockNumber) {
+			ensure_root(origin)?;
+			<Self as Store>::PruningCheckInterval::put(interval);
+		}
+
+		/// Restore the code of a para from an export, including any retained past code
+		/// and a planned upgrade. The para must not have any code, code history or planned
+		/// code yet.
+		///
+		/// All of the code in the export is checked as new code would be.
+		///
+		/// This does not register the para, which must be done separately.
+		///
+		/// Must be called from Root origin.
+		#[weight = (
+			10_000_000 + CODE_BYTE_WEIGHT * export.codes().map(|code| code.0.len() as Weight).sum::<Weight>(),
+			DispatchClass::Operational,
+		)]
+		pub fn force_import_para_code(origin, id: ParaId, export: ParaCodeExport<T::BlockNumber>) {
+			ensure_root(origin)?;
+			ensure!(
+				!Code::contains_key(&id) &&
+					!<Self as Store>::PastCodeMeta::contains_key(&id) &&
+					!<Self as Store>::FutureCodeUpgrades::contains_key(&id) &&
+					!<Self as Store>::FutureCode::contains_key(&id),
+				Error::<T>::ParaCodeAlreadyExists,
+			);
+			ensure!(
+				export.past.windows(2).all(|w| w[0].0 < w[1].0),
+				Error::<T>::PastCodeOutOfOrder,
+			);
+			ensure!(
+				match (export.last_pruned, export.past.first()) {
+					(Some(pruned), Some(&(oldest, _))) => pruned < oldest,
+					_ => true,
+				},
+				Error::<T>::PastCodeOutOfOrder,
+			);
+			if let Some((expected_at, _)) = export.future {
+				let last_change = export.past.last().map(|&(at, _)| at).or(export.last_pruned);
+				ensure!(
+					last_change.map_or(true, |at| at < expected_at),
+					Error::<T>::FutureCodeOutOfOrder,
+				);
+			}
+			for code in export.codes() {
+				ensure!(
+					code.0.len() as u32 <= T::MaxCodeSize::get(),
+					Error::<T>::ValidationCodeTooLarge,
+				);
+				ensure!(Self::code_is_acceptable(code), Error::<T>::NotWasm);
+				ensure!(!Self::code_is_rejected(code), Error::<T>::CodeRejected);
+			}
+
+			let ParaCodeExport { current, past, future, last_pruned } = export;
+			if let Some(code) = current {
+				Code::insert(&id, code);
+			}
+
+			// past code must be noted in ascending order of replacement.
+			for (replaced_at, code) in past {
+				Self::note_past_code(id, replaced_at, code);
+			}
+			if last_pruned.is_some() {
+				<Self as Store>::PastCodeMeta::mutate(&id, |meta| meta.last_pruned = last_pruned);
+			}
+
+			if let Some((expected_at, code)) = future {
+				<Self as Store>::FutureCodeUpgrades::insert(&id, expected_at);
+				<Self as Store>::FutureCode::insert(&id, code);
+			}
+		}
+
+		/// Set whether new validation code, whether for new paras or code upgrades, must
+		/// begin with the WASM magic number.
+		///
+		/// Must be called from Root origin.
+		#[weight = (10_000_000, DispatchClass::Operational)]
+		pub fn set_require_wasm_magic(origin, required: bool) {
+			ensure_root(origin)?;
+			<Self as Store>::RequireWasmMagic::put(required);
+		}
+
+		/// Schedule a code upgrade of a para, as though it were signalled by a candidate
+		/// included in this block. `expected_nonce` must match the para's current upgrade
+		/// nonce, so that a replayed or reordered request cannot schedule a stale upgrade.
+		///
+		/// Must be called from Root origin.
+		#[weight = (10_000_000, DispatchClass::Operational)]
+		pub fn force_schedule_code_upgrade(
+			origin,
+			id: ParaId,
+			new_code: ValidationCode,
+			expected_nonce: u32,
+		) {
+			ensure_root(origin)?;
+			ensure!(Self::upgrade_nonce(&id) == expected_nonce, Error::<T>::StaleUpgrade);
+			ensure!(Code::contains_key(&id), Error::<T>::UnregisteredPara);
+			ensure!(
+				Self::code_upgrade_schedule(&id).is_none(),
+				Error::<T>::DisallowedCodeUpgrade,
+			);
+			ensure!(
+				new_code.0.len() as u32 <= T::MaxCodeSize::get(),
+				Error::<T>::ValidationCodeTooLarge,
+			);
+			ensure!(Self::code_is_acceptable(&new_code), Error::<T>::NotWasm);
+			ensure!(Self::code_is_allowed(&new_code), Error::<T>::CodeNotAllowed);
+			ensure!(!Self::code_is_rejected(&new_code), Error::<T>::CodeRejected);
+			ensure!(!Self::is_noop_upgrade(&id, &new_code), Error::<T>::NoOpUpgrade);
+
+			Self::schedule_code_upgrade(id, &new_code, <system::Module<T>>::block_number());
+		}
+
+		/// Replace the code of a planned upgrade of a para.
+		///
+		/// The block at which the upgrade is expected is unchanged, and, as the para's
+		/// code history is not touched, so is the earliest block at which the para may
+		/// signal its next upgrade.
+		///
+		/// Must be called from Root origin.
+		#[weight = (10_000_000, DispatchClass::Operational)]
+		pub fn force_set_future_code(origin, id: ParaId, new_code: ValidationCode) {
+			ensure_root(origin)?;
+			ensure!(Self::code_upgrade_schedule(&id).is_some(), Error::<T>::NoPlannedUpgrade);
+			ensure!(
+				new_code.0.len() as u32 <= T::MaxCodeSize::get(),
+				Error::<T>::ValidationCodeTooLarge,
+			);
+			ensure!(Self::code_is_acceptable(&new_code), Error::<T>::NotWasm);
+			ensure!(!Self::code_is_rejected(&new_code), Error::<T>::CodeRejected);
+
+			FutureCode::insert(&id, new_code);
+		}
+
+		/// Move a planned upgrade of a para to a different block, without touching the code
+		/// to be upgraded to. The upgrade may be moved earlier or later, but not to or before
+		/// the para's most recent code change.
+		///
+		/// Must be called from Root origin.
+		#[weight = (10_000_000, DispatchClass::Operational)]
+		pub fn force_reschedule_upgrade(origin, id: ParaId, new_expected_at: T::BlockNumber) {
+			ensure_root(origin)?;
+			ensure!(Self::code_upgrade_schedule(&id).is_some(), Error::<T>::NoPlannedUpgrade);
+			ensure!(
+				Self::past_code_meta(&id).most_recent_change().map_or(true, |at| at < new_expected_at),
+				Error::<T>::DisallowedCodeUpgrade,
+			);
+
+			<Self as Store>::FutureCodeUpgrades::insert(&id, &new_expected_at);
+
+			let future_code_hash = T::Hashing::hash(&<Self as Store>::FutureCode::get(&id).0);
+			Self::deposit_event(RawEvent::CodeUpgradeScheduled(id, new_expected_at, future_code_hash));
+		}
+
+		/// Cancel the planned code upgrade of a para, e.g. after it has been found to
+		/// misbehave. This applies even if the para is paused or its upgrades are locked.
+		///
+		/// Must be called from Root origin.
+		#[weight = (10_000_000, DispatchClass::Operational)]
+		pub fn force_cancel_upgrade(origin, id: ParaId) {
+			ensure_root(origin)?;
+
+			if Self::discard_future_code(id) {
+				Self::deposit_event(RawEvent::UpgradeForciblyCancelled(id));
+			}
+		}
+
+		/// Immediately replace the current code of several paras at once, e.g. for a
+		/// coordinated hard fork. The replaced code of each para is retained as past code,
+		/// as for any other upgrade, and any planned code upgrade of the para is discarded.
+		///
+		/// Either all of the updates are applied, or none are.
+		///
+		/// Must be called from Root origin.
+		#[weight = (
+			10_000_000 * updates.len() as Weight +
+				CODE_BYTE_WEIGHT * updates.iter().map(|(_, code)| code.0.len() as Weight).sum::<Weight>(),
+			DispatchClass::Operational,
+		)]
+		pub fn force_set_current_codes(origin, updates: Vec<(ParaId, ValidationCode)>) {
+			ensure_root(origin)?;
+			ensure!(
+				updates.len() <= T::MaxForcedCodeUpdates::get() as usize,
+				Error::<T>::TooManyCodeUpdates,
+			);
+
+			let mut ids: Vec<_> = updates.iter().map(|&(id, _)| id).collect();
+			ids.sort();
+			ensure!(ids.windows(2).all(|w| w[0] != w[1]), Error::<T>::DuplicateParaId);
+
+			for (id, new_code) in &updates {
+				ensure!(Code::contains_key(id), Error::<T>::UnregisteredPara);
+				ensure!(
+					new_code.0.len() as u32 <= T::MaxCodeSize::get(),
+					Error::<T>::ValidationCodeTooLarge,
+				);
+				ensure!(Self::code_is_acceptable(new_code), Error::<T>::NotWasm);
+				ensure!(Self::code_is_allowed(new_code), Error::<T>::CodeNotAllowed);
+				ensure!(!Self::code_is_rejected(new_code), Error::<T>::CodeRejected);
+			}
+
+			let now = <system::Module<T>>::block_number();
+			for (id, new_code) in updates {
+				// a planned upgrade would otherwise replace the forced code once due.
+				Self::discard_future_code(id);
+				Self::do_code_upgrade(id, now, &new_code);
+			}
+		}
+
+		/// Apply the planned code upgrades of the given paras which are due by the current
+		/// block, as though a new head of each had been noted in its context. Paras without
+		/// a due upgrade are skipped.
+		///
+		/// Must be called from Root origin.
+		#[weight = (10_000_000 * paras.len() as Weight, DispatchClass::Operational)]
+		pub fn force_advance_pending_upgrades(origin, paras: Vec<ParaId>) {
+			ensure_root(origin)?;
+			ensure!(
+				paras.len() <= T::MaxForcedCodeUpdates::get() as usize,
+				Error::<T>::TooManyCodeUpdates,
+			);
+
+			let now = <system::Module<T>>::block_number();
+			for id in paras {
+				Self::apply_due_upgrade(id, now);
+			}
+		}
+
+		/// Prune the past code of a para which was replaced at or before `up_to`, ahead of
+		/// the end of its slashing window.
+		///
+		/// This should only be used when the code is known to be no longer needed, as it
+		/// removes code which may be needed to check disputed or reported candidates.
+		///
+		/// Must be called from Root origin.
+		#[weight = (10_000_000, DispatchClass::Operational)]
+		pub fn force_prune_para_past_code(origin, id: ParaId, up_to: T::BlockNumber) {
+			ensure_root(origin)?;
+
+			<Self as Store>::PastCodePruning::mutate(|pruning_tasks| {
+				pruning_tasks.retain(|&(para_id, at)| para_id != id || at > up_to)
+			});
+
+			let mut pruned_size = 0;
+			let full_deactivate = <Self as Store>::PastCodeMeta::mutate(&id, |meta| {
+				for pruned_repl_at in meta.prune_up_to(up_to) {
+					pruned_size += Self::remove_past_code(id, pruned_repl_at);
+				}
+				debug_assert!(meta.is_consistent());
+
+				meta.most_recent_change().is_none() && Self::parachain_head(&id).is_none()
+			});
+			<Self as Store>::RetainedPastCodeSize::mutate(|size| *size = size.saturating_sub(pruned_size));
+
+			if full_deactivate {
+				<Self as Store>::PastCodeMeta::remove(&id);
+			}
+		}
+
+		/// Overwrite the block number of the last pruned code replacement of a para. This is
+		/// for recovering from a corrupted `PastCodeMeta`, where a wrong value would cause
+		/// retained past code to be treated as pruned, or vice versa.
+		///
+		/// Must be called from Root origin.
+		#[weight = (10_000_000, DispatchClass::Operational)]
+		pub fn force_fix_last_pruned(origin, id: ParaId, value: Option<T::BlockNumber>) {
+			ensure_root(origin)?;
+			<Self as Store>::PastCodeMeta::mutate(&id, |meta| meta.last_pruned = value);
+		}
+
+		/// Set or clear the code to be used in place of the code of a para at the given block.
+		/// While set, it is returned by `parachain_code_at` for that block, regardless of the
+		/// code history of the para. The substitute is checked as new code would be.
+		///
+		/// Substitutes are not pruned along with the past code of the para, but only removed
+		/// when the para is cleaned up. Governance must clear a substitute once it is no longer
+		/// needed.
+		///
+		/// Must be called from Root origin.
+		#[weight = (10_000_000, DispatchClass::Operational)]
+		pub fn set_code_substitute(
+			origin,
+			id: ParaId,
+			at: T::BlockNumber,
+			substitute: Option<ValidationCode>,
+		) {
+			ensure_root(origin)?;
+
+			match substitute {
+				Some(code) => {
+					ensure!(
+						code.0.len() as u32 <= T::MaxCodeSize::get(),
+						Error::<T>::ValidationCodeTooLarge,
+					);
+					ensure!(Self::code_is_acceptable(&code), Error::<T>::NotWasm);
+					ensure!(!Self::code_is_rejected(&code), Error::<T>::CodeRejected);
+					<Self as Store>::CodeSubstitutes::insert(&id, &at, code);
+				}
+				None => <Self as Store>::CodeSubstitutes::remove(&id, &at),
+			}
+		}
+
+		/// Set the hashes of validation code which has been vetted by governance.
+		///
+		/// Must be called from Root origin.
+		#[weight = (10_000_000, DispatchClass::Operational)]
+		pub fn set_code_hash_allowlist(origin, allowlist: Vec<T::Hash>) {
+			ensure_root(origin)?;
+			<Self as Store>::CodeHashAllowlist::put(allowlist);
+		}
+
+		/// Set whether code upgrades must be to code whose hash is in the allowlist.
+		///
+		/// Must be called from Root origin.
+		#[weight = (10_000_000, DispatchClass::Operational)]
+		pub fn set_enforce_code_allowlist(origin, enforce: bool) {
+			ensure_root(origin)?;
+			<Self as Store>::EnforceCodeAllowlist::put(enforce);
+		}
+
+		/// Mark validation code as invalid, e.g. after a dispute has concluded that it produced
+		/// an invalid block. No para may upgrade to code with the given hash afterwards, and
+		/// planned upgrades to it are discarded when due.
+		///
+		/// Must be called from Root origin.
+		#[weight = (10_000_000, DispatchClass::Operational)]
+		pub fn force_mark_code_invalid(origin, code_hash: T::Hash) {
+			ensure_root(origin)?;
+			<Self as Store>::RejectedCodeHashes::insert(&code_hash, true);
+		}
+
+		/// Lock or unlock code upgrades of a para. While locked, a planned code upgrade
+		/// is not applied, but remains pending until the para is unlocked, and the para
+		/// may not signal new code.
+		///
+		/// Must be called from Root origin.
+		#[weight = (10_000_000, DispatchClass::Operational)]
+		pub fn set_code_upgrade_lock(origin, id: ParaId, locked: bool) {
+			ensure_root(origin)?;
+			if locked {
+				CodeUpgradeLocked::insert(&id, true);
+			} else {
+				CodeUpgradeLocked::remove(&id);
+			}
+		}
+
+		/// Note a new head for a para as though it had been executed in the context of the
+		/// given relay-chain block, applying any code upgrade which is due by then. The block
+		/// must not precede the most recent code change of the para.
+		///
+		/// Must be called from Root origin.
+		#[weight = (10_000_000, DispatchClass::Operational)]
+		pub fn force_note_new_head_at(
+			origin,
+			id: ParaId,
+			head: HeadData,
+			context: T::BlockNumber,
+		) {
+			ensure_root(origin)?;
+			ensure!(
+				Self::past_code_meta(&id).most_recent_change().map_or(true, |at| at <= context),
+				Error::<T>::ExecutionContextOutdated,
+			);
+			Self::try_note_new_head(id, head, context)?;
+		}
 	}
 }
 
@@ -692,27 +1388,93 @@ impl<T: Trait> Module<T> {
 		initial_head_data: HeadData,
 	) {
 		<Code>::insert(id, code);
-		<Heads>::insert(id, initial_head_data);
+		Self::put_head(id, initial_head_data);
+		<Self as Store>::RegisteredAt::insert(id, <system::Module<T>>::block_number());
 	}
 
 	/// Cleanup all storage related to a para. Some pieces of data may remain
 	/// available in the on-chain state.
+	///
+	/// Returns the weight consumed.
 	pub fn cleanup_para(
 		id: ParaId,
-	) {
-		let code = <Code>::take(id);
-		<Heads>::remove(id);
+	) -> Weight {
+		let mut weight = T::DbWeight::get().reads_writes(5, 10);
+
+		// the code of a paused para is held aside, but is archived all the same.
+		let paused_code = <Self as Store>::PausedCode::take(&id);
+		let code = <Code>::take(id).or(paused_code);
+		if let Some(head) = <Heads>::take(id) {
+			TotalHeadBytes::mutate(|total| *total = total.saturating_sub(head.0.len() as u64));
+
+			let head_hash = T::Hashing::hash(&head.0);
+			Self::deposit_event(RawEvent::ParaOffboardedWithHead(id, head_hash));
+		}
 
 		// clean up from all code-upgrade maps.
 		// we don't clean up the meta or planned-code maps as that's handled
 		// by the pruning process.
 		if let Some(_planned_future_at) = <Self as Store>::FutureCodeUpgrades::take(&id) {
-			<Self as Store>::FutureCode::remove(&id);
+			let future_code = <Self as Store>::FutureCode::take(&id);
+			weight += T::DbWeight::get().reads_writes(1, 1);
+
+			let future_code_hash = T::Hashing::hash(&future_code.0);
+			Self::deposit_event(RawEvent::UpgradeDiscardedOnOffboard(id, future_code_hash));
 		}
+		UpgradeCount::remove(&id);
+		UpgradeNonce::remove(&id);
+		<Self as Store>::RegisteredAt::remove(&id);
+		CodeUpgradeLocked::remove(&id);
+		<Self as Store>::CodeSubstitutes::remove_prefix(&id);
 
 		if let Some(code) = code {
-			Self::note_past_code(id, <system::Module<T>>::block_number(), code);
+			weight += Self::note_past_code(id, <system::Module<T>>::block_number(), code);
 		}
+
+		weight
+	}
+
+	/// An upper bound on the weight consumed by `cleanup_para`: that of cleaning up a para
+	/// with a planned code upgrade whose code is archived as past code.
+	pub fn max_cleanup_para_weight() -> Weight {
+		T::DbWeight::get().reads_writes(5, 10) +
+			T::DbWeight::get().reads_writes(1, 1) +
+			T::DbWeight::get().reads_writes(3, 4)
+	}
+
+	/// Pause a para. Its current code is held aside rather than archived, and its head is
+	/// kept, so that it can later be resumed with `resume_para`. Any planned code upgrade
+	/// is discarded.
+	pub fn pause_para(id: ParaId) {
+		if let Some(code) = <Code>::take(id) {
+			<Self as Store>::PausedCode::insert(&id, code);
+		}
+
+		Self::discard_future_code(id);
+	}
+
+	/// Discard the planned code upgrade of a para, if any. Returns whether there was one.
+	pub fn discard_future_code(id: ParaId) -> bool {
+		match <Self as Store>::FutureCodeUpgrades::take(&id) {
+			Some(_planned_future_at) => {
+				<Self as Store>::FutureCode::remove(&id);
+				true
+			}
+			None => false,
+		}
+	}
+
+	/// Whether the para is paused, with its code held aside.
+	pub fn is_paused(id: &ParaId) -> bool {
+		<Self as Store>::PausedCode::contains_key(id)
+	}
+
+	/// Resume a paused para, re-installing the code it had when it was paused.
+	pub fn resume_para(id: ParaId) -> DispatchResult {
+		let code = <Self as Store>::PausedCode::take(&id).ok_or(Error::<T>::ParaNotPaused)?;
+		<Code>::insert(&id, code);
+
+		Ok(())
 	}
 
 	// note replacement of the code of para with given `id`, which occured in the
@@ -721,12 +1483,29 @@ impl<T: Trait> Module<T> {
 	// `at` for para-triggered replacement is the block number of the relay-chain
 	// block in whose context the parablock was executed
 	// (i.e. number of `relay_parent` in the receipt)
-	fn note_past_code(id: ParaId, at: T::BlockNumber, old_code: ValidationCode) {
-		<Self as Store>::PastCodeMeta::mutate(&id, |past_meta| {
+	//
+	// returns the weight consumed.
+	fn note_past_code(id: ParaId, at: T::BlockNumber, old_code: ValidationCode) -> Weight {
+		let noted = <Self as Store>::PastCodeMeta::mutate(&id, |past_meta| {
+			// code replaced at or before the most recent replacement was the current code
+			// for no block at all, so there is nothing to retain. in particular, when the code
+			// of a para is replaced several times in one block, only the code replaced first
+			// was ever used, and it is already retained.
+			if past_meta.most_recent_change().map_or(false, |recent| at <= recent) {
+				return false
+			}
+
 			past_meta.note_replacement(at);
+			debug_assert!(past_meta.is_consistent());
+			true
 		});
+		if !noted {
+			return T::DbWeight::get().reads_writes(1, 1)
+		}
 
+		let code_size = old_code.0.len() as u64;
 		<Self as Store>::PastCode::insert(&(id, at), old_code);
+		<Self as Store>::RetainedPastCodeSize::mutate(|size| *size = size.saturating_add(code_size));
 
 		// Schedule pruning for this past-code to be removed as soon as it
 		// exits the slashing window.
@@ -734,31 +1513,93 @@ impl<T: Trait> Module<T> {
 			let insert_idx = pruning.binary_search_by_key(&at, |&(_, b)| b)
 				.unwrap_or_else(|idx| idx);
 			pruning.insert(insert_idx, (id, at));
-		})
+		});
+
+		T::DbWeight::get().reads_writes(3, 4)
+	}
+
+	// remove the past code of a para which was replaced at the given block, returning its
+	// size in bytes. the caller is responsible for updating `RetainedPastCodeSize`.
+	fn remove_past_code(id: ParaId, at: T::BlockNumber) -> u64 {
+		<Self as Store>::PastCode::take(&(id, at)).map_or(0, |code| code.0.len() as u64)
+	}
+
+	/// Whether the configured `ValidationUpgradeDelay` is no longer than the `SlashPeriod`.
+	///
+	/// If it were longer, the code replaced by an upgrade could be pruned before the upgrade
+	/// is applied.
+	pub fn upgrade_delay_within_slash_period() -> bool {
+		T::ValidationUpgradeDelay::get() <= T::SlashPeriod::get()
+	}
+
+	/// Check the consistency of the storage of this module: every task in the past code
+	/// pruning queue must refer to a replacement noted in the para's `PastCodeMeta`.
+	#[cfg(any(feature = "try-runtime", test))]
+	pub fn do_try_state() -> Result<(), &'static str> {
+		for (id, at) in Self::past_code_pruning_tasks() {
+			if !Self::past_code_meta(&id).upgrade_times.contains(&at) {
+				return Err("past code pruning task has no matching `PastCodeMeta` entry");
+			}
+		}
+
+		Ok(())
 	}
 
-	// does old code pruning.
-	fn do_old_code_pruning(now: T::BlockNumber) {
+	// The height of any code replacements we no longer should keep around at `now`, if any.
+	// code replaced at or before this height has exited the slashing window.
+	fn retention_window_bound(now: T::BlockNumber) -> Option<T::BlockNumber> {
+		// `now > slash_period` below, so this cannot underflow.
 		let slash_period = T::SlashPeriod::get();
-		if now <= slash_period { return }
+		if now <= slash_period { return None }
+
+		Some(now - slash_period - One::one())
+	}
+
+	// does old code pruning. returns whether the pruning queue was scanned.
+	fn do_old_code_pruning(now: T::BlockNumber) -> bool {
+		// avoid decoding the queue when there is nothing to prune.
+		if <Self as Store>::PastCodePruning::decode_len().map_or(true, |len| len == 0) {
+			return false
+		}
+
+		// only scan the queue every `PruningCheckInterval` blocks.
+		let check_interval = Self::pruning_check_interval();
+		if check_interval > One::one() && !(now % check_interval).is_zero() {
+			return false
+		}
 
-		// The height of any changes we no longer should keep around.
-		let pruning_height = now - (slash_period + One::one());
+		let pruning_height = match Self::retention_window_bound(now) {
+			Some(pruning_height) => pruning_height,
+			None => return false,
+		};
 
+		let mut pruned_size = 0;
 		<Self as Store>::PastCodePruning::mutate(|pruning_tasks: &mut Vec<(_, T::BlockNumber)>| {
-			let pruning_tasks_to_do = {
+			let pruning_tasks_to_do: Vec<_> = {
 				// find all past code that has just exited the pruning window.
 				let up_to_idx = pruning_tasks.iter()
 					.take_while(|&(_, at)| at <= &pruning_height)
 					.count();
-				pruning_tasks.drain(..up_to_idx)
+				pruning_tasks.drain(..up_to_idx).collect()
 			};
 
-			for (para_id, _) in pruning_tasks_to_do {
+			// tasks which were held back stay at the front of the queue. once the pruning of
+			// a para is held back, so is the pruning of its later replacements, as the past
+			// code of a para must be pruned in order.
+			let mut held_back = Vec::new();
+			for (para_id, replaced_at) in pruning_tasks_to_do {
+				if held_back.iter().any(|&(id, _)| id == para_id) ||
+					!T::OnBeforeCodePrune::may_prune(para_id, replaced_at)
+				{
+					held_back.push((para_id, replaced_at));
+					continue
+				}
+
 				let full_deactivate = <Self as Store>::PastCodeMeta::mutate(&para_id, |meta| {
-					for pruned_repl_at in meta.prune_up_to(pruning_height) {
-						<Self as Store>::PastCode::remove(&(para_id, pruned_repl_at));
+					for pruned_repl_at in meta.prune_up_to(replaced_at) {
+						pruned_size += Self::remove_past_code(para_id, pruned_repl_at);
 					}
+					debug_assert!(meta.is_consistent());
 
 					meta.most_recent_change().is_none() && Self::parachain_head(&para_id).is_none()
 				});
@@ -769,34 +1610,168 @@ impl<T: Trait> Module<T> {
 					<Self as Store>::PastCodeMeta::remove(&para_id);
 				}
 			}
+
+			held_back.extend(pruning_tasks.drain(..));
+			*pruning_tasks = held_back;
 		});
+		<Self as Store>::RetainedPastCodeSize::mutate(|size| *size = size.saturating_sub(pruned_size));
+
+		true
 	}
 
 	// Performs a code upgrade of a parachain.
 	fn do_code_upgrade(id: ParaId, at: T::BlockNumber, new_code: &ValidationCode) {
 		let old_code = Self::parachain_code(&id).unwrap_or_default();
 		Code::insert(&id, new_code);
+		UpgradeCount::mutate(&id, |count| *count = count.saturating_add(1));
 
 		Self::note_past_code(id, at, old_code);
 	}
 
-	/// Get a `SigningContext` with a current `SessionIndex` and parent hash.
-	pub fn signing_context() -> SigningContext {
-		let session_index = <session::Module<T>>::current_index();
-		let parent_hash = <system::Module<T>>::parent_hash();
+	// The block at which a code upgrade signalled by a candidate with the given relay-parent
+	// is expected to be applied. This saturates at the maximum block number, in which case
+	// the upgrade is only applied by a candidate executed in the context of that block.
+	fn expected_upgrade_at(relay_parent: T::BlockNumber) -> T::BlockNumber {
+		relay_parent.saturating_add(T::ValidationUpgradeDelay::get())
+	}
 
-		SigningContext {
-			session_index,
-			parent_hash: T::BlockHashConversion::convert(parent_hash),
+	// Schedule a code upgrade of a para, signalled by a candidate with the given relay-parent.
+	// The upgrade is applied immediately if there is no delay.
+	fn schedule_code_upgrade(id: ParaId, new_code: &ValidationCode, relay_parent: T::BlockNumber) {
+		UpgradeNonce::mutate(&id, |nonce| *nonce = nonce.wrapping_add(1));
+
+		let expected_at = Self::expected_upgrade_at(relay_parent);
+		if T::ValidationUpgradeDelay::get().is_zero() {
+			Self::do_code_upgrade(id, relay_parent, new_code);
+		} else {
+			<Self as Store>::FutureCodeUpgrades::insert(&id, &expected_at);
+			FutureCode::insert(&id, new_code);
+
+			let new_code_hash = T::Hashing::hash(&new_code.0);
+			Self::deposit_event(RawEvent::CodeUpgradeScheduled(id, expected_at, new_code_hash));
 		}
 	}
 
-	/// Submit a double vote report.
-	pub fn submit_double_vote_report(
-		report: DoubleVoteReport<T::Proof>,
-	) -> Option<()> {
-		Signer::<T, T::AuthorityId>::all_accounts()
-			.send_signed_transaction(
+	// Note a new head of a para, like `note_new_head`, but refuse an execution context after
+	// the current block, which no real candidate can have. Acting on such a context could
+	// apply a planned code upgrade early.
+	fn try_note_new_head(
+		id: ParaId,
+		new_head: HeadData,
+		execution_context: T::BlockNumber,
+	) -> DispatchResult {
+		ensure!(
+			execution_context <= <system::Module<T>>::block_number(),
+			Error::<T>::ExecutionContextInFuture,
+		);
+
+		Self::note_new_head(id, new_head, execution_context);
+		Ok(())
+	}
+
+	// Note a new head of a para, produced in the context of the relay-chain block
+	// with the given number. This also applies any planned code upgrade which is due.
+	fn note_new_head(id: ParaId, new_head: HeadData, execution_context: T::BlockNumber) {
+		let head_hash = T::Hashing::hash(&new_head.0);
+
+		Self::put_head(id, new_head);
+		<Self as Store>::HeadsChangedThisBlock::mutate(|changed|
+			if let Err(idx) = changed.binary_search(&id) {
+				changed.insert(idx, id);
+			}
+		);
+
+		Self::deposit_event(RawEvent::HeadAccepted(id, head_hash, execution_context));
+
+		Self::apply_due_upgrade(id, execution_context);
+	}
+
+	// set `TotalHeadBytes` from the `Heads` which predate it. returns the weight consumed.
+	fn migrate_total_head_bytes() -> Weight {
+		use frame_support::IterableStorageMap;
+
+		let (heads, total) = <Heads as IterableStorageMap<ParaId, HeadData>>::iter()
+			.fold((0u64, 0u64), |(heads, total), (_, head)| {
+				(heads + 1, total.saturating_add(head.0.len() as u64))
+			});
+
+		TotalHeadBytes::put(total);
+		T::DbWeight::get().reads_writes(heads, 1)
+	}
+
+	// set `RetainedPastCodeSize` from the past code awaiting pruning which predates it.
+	// returns the weight consumed.
+	fn migrate_retained_past_code_size() -> Weight {
+		let pruning_tasks = <Self as Store>::PastCodePruning::get();
+		let total = pruning_tasks.iter()
+			.filter_map(Self::past_code_len)
+			.fold(0u64, |total, len| total.saturating_add(len as u64));
+
+		<Self as Store>::RetainedPastCodeSize::put(total);
+		T::DbWeight::get().reads_writes(pruning_tasks.len() as u64 + 1, 1)
+	}
+
+	// the length in bytes of the given past code, read from the length prefix of its
+	// encoding so that the code itself is not decoded.
+	fn past_code_len(key: &(ParaId, T::BlockNumber)) -> Option<u32> {
+		let key = <Self as Store>::PastCode::hashed_key_for(key);
+		let mut prefix = [0u8; 5];
+		sp_io::storage::read(&key, &mut prefix, 0)?;
+
+		codec::Compact::<u32>::decode(&mut &prefix[..]).ok().map(|len| len.0)
+	}
+
+	// Set the head of a para, keeping `TotalHeadBytes` up to date.
+	fn put_head(id: ParaId, new_head: HeadData) {
+		let new_size = new_head.0.len() as u64;
+		let old_size = Heads::mutate(&id, |head| head.replace(new_head)).map_or(0, |head| head.0.len() as u64);
+		TotalHeadBytes::mutate(|total| *total = total.saturating_add(new_size).saturating_sub(old_size));
+	}
+
+	// Apply the planned code upgrade of a para, if it is due by the given relay-chain block.
+	fn apply_due_upgrade(id: ParaId, execution_context: T::BlockNumber) {
+		if let Some(expected_at) = Self::code_upgrade_schedule(&id) {
+			// a locked or paused para keeps its planned upgrade pending until it is unlocked
+			// or resumed, as its code is frozen by governance.
+			let frozen = Self::code_upgrade_locked(&id) ||
+				<Self as Store>::PausedCode::contains_key(&id);
+			// the code may have been changed by governance after the block in whose context
+			// the head was executed, in which case the upgrade waits for a later head so as
+			// not to be out of order with the code history of the para.
+			let outdated = Self::past_code_meta(&id).most_recent_change()
+				.map_or(false, |at| execution_context < at);
+			if expected_at <= execution_context && !frozen && !outdated {
+				let new_code = FutureCode::take(&id);
+				<Self as Store>::FutureCodeUpgrades::remove(&id);
+
+				// the maximum code size may have been lowered, or the code marked as invalid,
+				// since the upgrade was scheduled.
+				if new_code.0.len() as u32 > T::MaxCodeSize::get() || Self::code_is_rejected(&new_code) {
+					Self::deposit_event(RawEvent::UpgradeRejectedAtApplication(id));
+				} else {
+					Self::do_code_upgrade(id, execution_context, &new_code);
+				}
+			}
+		}
+	}
+
+	/// Get a `SigningContext` with a current `SessionIndex` and parent hash.
+	pub fn signing_context() -> SigningContext {
+		let session_index = <session::Module<T>>::current_index();
+		let parent_hash = <system::Module<T>>::parent_hash();
+
+		SigningContext {
+			session_index,
+			parent_hash: T::BlockHashConversion::convert(parent_hash),
+		}
+	}
+
+	/// Submit a double vote report.
+	pub fn submit_double_vote_report(
+		report: DoubleVoteReport<T::Proof>,
+	) -> Option<()> {
+		Signer::<T, T::AuthorityId>::all_accounts()
+			.send_signed_transaction(
 				move |_account| {
 					Call::report_double_vote(report.clone())
 				}
@@ -873,7 +1848,6 @@ impl<T: Trait> Module<T> {
 
 		for head in heads.iter() {
 			let id = head.parachain_index();
-			Heads::insert(id, &head.candidate.head_data);
 
 			// Queue up upwards messages (from parachains to relay chain).
 			Self::queue_upward_messages(
@@ -1051,21 +2025,26 @@ impl<T: Trait> Module<T> {
 				Scheduling::Dynamic => return None, // parathreads can't upgrade code.
 			}
 
+			// a locked para may not replace its held-back upgrade, as its code is frozen by
+			// governance.
+			if Self::code_upgrade_locked(id) {
+				return None
+			}
+
 			// if perceived-height were not the parent of `now`, then this should
 			// not be drawn from current-runtime configuration. however the sanity-check
 			// above prevents that.
 			let min_upgrade_frequency = T::ValidationUpgradeFrequency::get();
-			let upgrade_delay = T::ValidationUpgradeDelay::get();
 
 			let no_planned = Self::code_upgrade_schedule(id)
 				.map_or(true, |expected: T::BlockNumber| expected <= perceived_height);
 
 			let can_upgrade_code = no_planned &&
 				Self::past_code_meta(id).most_recent_change()
-					.map_or(true, |at| at + min_upgrade_frequency < perceived_height);
+					.map_or(true, |at| at.saturating_add(min_upgrade_frequency) < perceived_height);
 
 			if can_upgrade_code {
-				let applied_at = perceived_height + upgrade_delay;
+				let applied_at = Self::expected_upgrade_at(perceived_height);
 				Some(T::BlockNumberConversion::convert(applied_at))
 			} else {
 				None
@@ -1092,16 +2071,362 @@ impl<T: Trait> Module<T> {
 
 	/// Fetch the code used for verifying a parachain at a particular height.
 	pub fn parachain_code_at(id: &ParaId, at: T::BlockNumber) -> Option<ValidationCode> {
+		Self::parachain_code_at_with_meta(id, at, &Self::past_code_meta(id))
+	}
+
+	/// Fetch the code used for verifying each of the given parachains at the given block
+	/// numbers. The results are the same as those of `parachain_code_at` for each query, but
+	/// the past code metadata of each para is only read once.
+	pub fn parachain_codes_at(queries: &[(ParaId, T::BlockNumber)]) -> Vec<Option<ValidationCode>> {
+		let mut metas = sp_std::collections::btree_map::BTreeMap::new();
+
+		queries.iter()
+			.map(|&(id, at)| {
+				let meta = metas.entry(id).or_insert_with(|| Self::past_code_meta(&id));
+				Self::parachain_code_at_with_meta(&id, at, meta)
+			})
+			.collect()
+	}
+
+	/// Get the distinct code needed to validate candidates of the given paras in the context
+	/// of the given blocks, each along with its hash. Each code is returned once, in the
+	/// order in which it is first needed. Contexts for which no code is known are skipped.
+	pub fn codes_for_contexts(requests: &[(ParaId, T::BlockNumber)]) -> Vec<(T::Hash, ValidationCode)> {
+		let mut codes: Vec<(T::Hash, ValidationCode)> = Vec::new();
+		for code in Self::parachain_codes_at(requests).into_iter().flatten() {
+			let code_hash = T::Hashing::hash(&code.0);
+			if !codes.iter().any(|(hash, _)| *hash == code_hash) {
+				codes.push((code_hash, code));
+			}
+		}
+
+		codes
+	}
+
+	fn parachain_code_at_with_meta(
+		id: &ParaId,
+		at: T::BlockNumber,
+		meta: &ParaPastCodeMeta<T::BlockNumber>,
+	) -> Option<ValidationCode> {
+		if let Some(substitute) = Self::code_substitute(id, at) {
+			return Some(substitute)
+		}
+
 		// note - we don't check that the parachain is currently registered
 		// as this might be a deregistered parachain whose old code should still
 		// stick around on-chain for some time.
-		Self::past_code_meta(id).code_at(at).and_then(|to_use| match to_use {
+		let to_use = meta.code_at(at);
+		if to_use.is_none() {
+			T::OnCodeUnavailable::on_code_unavailable(*id, at);
+		}
+
+		to_use.and_then(|to_use| match to_use {
 			UseCodeAt::Current => Self::parachain_code(id),
 			UseCodeAt::ReplacedAt(replaced_at) =>
 				<Self as Store>::PastCode::get(&(*id, replaced_at)),
 		})
 	}
 
+	/// Fetch the code which a candidate of a para with the given relay-parent must be
+	/// validated against.
+	///
+	/// A planned code upgrade which is due by the relay-parent is not applied until a
+	/// candidate executed in that context is included, so the candidate which triggers an
+	/// upgrade is itself validated against the code being replaced.
+	pub fn code_for_candidate(id: &ParaId, relay_parent: T::BlockNumber) -> Option<ValidationCode> {
+		Self::parachain_code_at(id, relay_parent)
+	}
+
+	/// Whether the given code is acceptable as new validation code. If `RequireWasmMagic`
+	/// is set, the code must begin with the WASM magic number.
+	pub fn code_is_acceptable(code: &ValidationCode) -> bool {
+		!Self::require_wasm_magic() || code.0.starts_with(&WASM_MAGIC)
+	}
+
+	/// Whether the given code may be upgraded to. If `EnforceCodeAllowlist` is set, the
+	/// hash of the code must be in the `CodeHashAllowlist`.
+	pub fn code_is_allowed(code: &ValidationCode) -> bool {
+		!Self::enforce_code_allowlist() ||
+			Self::code_hash_allowlist().contains(&T::Hashing::hash(&code.0))
+	}
+
+	/// Whether the given code has been marked as invalid, and so may not be upgraded to.
+	pub fn code_is_rejected(code: &ValidationCode) -> bool {
+		Self::code_hash_rejected(T::Hashing::hash(&code.0))
+	}
+
+	/// Whether upgrading the para to the given code would leave its code unchanged. Such an
+	/// upgrade would only take up space for a redundant copy of the code as past code.
+	pub fn is_noop_upgrade(id: &ParaId, new_code: &ValidationCode) -> bool {
+		Self::parachain_code(id).map_or(false, |code|
+			T::Hashing::hash(&code.0) == T::Hashing::hash(&new_code.0)
+		)
+	}
+
+	/// Get the size in bytes and the hash of the current code of a para, if any.
+	pub fn current_code_meta(id: &ParaId) -> Option<(u32, T::Hash)> {
+		Self::parachain_code(id).map(|code| (code.0.len() as u32, T::Hashing::hash(&code.0)))
+	}
+
+	/// Get the size in bytes and the hash of the head of a para, if any.
+	pub fn head_meta(id: &ParaId) -> Option<(u32, T::Hash)> {
+		Self::parachain_head(id).map(|head| (head.0.len() as u32, T::Hashing::hash(&head.0)))
+	}
+
+	/// Whether the current code of the para has a hash in the `CodeHashAllowlist`.
+	pub fn runs_allowed_code(id: &ParaId) -> bool {
+		Self::parachain_code(id).map_or(false, |code|
+			Self::code_hash_allowlist().contains(&T::Hashing::hash(&code.0))
+		)
+	}
+
+	/// The number of blocks since the current code of a para took effect, either through
+	/// the most recent code upgrade or, if there has been none, through registration.
+	///
+	/// Returns `None` if the para has no current code.
+	pub fn current_code_age(id: &ParaId, now: T::BlockNumber) -> Option<T::BlockNumber> {
+		if !Code::contains_key(id) {
+			return None
+		}
+
+		let meta = Self::past_code_meta(id);
+		let since = meta.most_recent_change()
+			.or(meta.last_pruned)
+			.unwrap_or_else(|| <Self as Store>::RegisteredAt::get(id));
+
+		Some(now.saturating_sub(since))
+	}
+
+	/// Get the parameters which currently apply to a code upgrade of a para.
+	pub fn upgrade_parameters(id: &ParaId) -> UpgradeParameters<T::BlockNumber> {
+		// candidates included in this block are executed in the context of its parent.
+		let perceived_height = <system::Module<T>>::block_number().saturating_sub(One::one());
+
+		// an upgrade may be signalled once the perceived height is past the most recent
+		// change by more than the upgrade frequency.
+		let cooldown_remaining = Self::past_code_meta(id).most_recent_change()
+			.map(|at| at.saturating_add(T::ValidationUpgradeFrequency::get()).saturating_add(One::one()))
+			.map_or(Zero::zero(), |allowed_at| allowed_at.saturating_sub(perceived_height));
+
+		UpgradeParameters {
+			min_delay: T::ValidationUpgradeDelay::get(),
+			max_code_size: T::MaxCodeSize::get(),
+			cooldown_remaining,
+			locked: Self::code_upgrade_locked(id),
+		}
+	}
+
+	/// The blocks at which the past code of a para which is pending pruning was replaced,
+	/// in ascending order. Each is pruned once it exits the slashing window.
+	pub fn para_pruning_tasks(id: &ParaId) -> Vec<T::BlockNumber> {
+		Self::past_code_pruning_tasks().into_iter()
+			.filter(|&(para_id, _)| para_id == *id)
+			.map(|(_, replaced_at)| replaced_at)
+			.collect()
+	}
+
+	/// The earliest block at which the oldest retained past code of a para is pruned, or
+	/// `None` if the para has no past code. From then on, candidates validated against that
+	/// code can no longer be checked.
+	pub fn para_retention_floor(id: &ParaId) -> Option<T::BlockNumber> {
+		Self::past_code_meta(id).upgrade_times.last().map(|&oldest|
+			oldest.saturating_add(T::SlashPeriod::get()).saturating_add(One::one())
+		)
+	}
+
+	/// The block number of the next replaced code to be pruned, if any.
+	pub fn next_prune_block() -> Option<T::BlockNumber> {
+		Self::past_code_pruning_tasks().first().map(|&(_, replaced_at)| replaced_at)
+	}
+
+	/// Export the current, past and planned code of a para.
+	#[cfg(feature = "std")]
+	pub fn export_para_code(id: &ParaId) -> ParaCodeExport<T::BlockNumber> {
+		let meta = Self::past_code_meta(id);
+		let past = meta.upgrade_times.iter()
+			.rev()
+			.filter_map(|&at| <Self as Store>::PastCode::get(&(*id, at)).map(|code| (at, code)))
+			.collect();
+
+		let future = Self::code_upgrade_schedule(id)
+			.map(|expected_at| (expected_at, <Self as Store>::FutureCode::get(id)));
+
+		ParaCodeExport {
+			current: Self::parachain_code(id),
+			past,
+			future,
+			last_pruned: meta.last_pruned,
+		}
+	}
+
+	/// Count the number of distinct validation code blobs, by hash, currently in use by
+	/// any para.
+	#[cfg(feature = "std")]
+	pub fn distinct_code_versions() -> u32 {
+		use frame_support::IterableStorageMap;
+
+		<Code as IterableStorageMap<ParaId, ValidationCode>>::iter()
+			.map(|(_, code)| T::Hashing::hash(&code.0))
+			.collect::<std::collections::BTreeSet<_>>()
+			.len() as u32
+	}
+
+	/// Count the entries in each of the storage maps holding the code and heads of paras.
+	#[cfg(feature = "std")]
+	pub fn storage_entry_counts() -> EntryCounts {
+		use frame_support::IterableStorageMap;
+
+		EntryCounts {
+			current_code: <Code as IterableStorageMap<ParaId, ValidationCode>>::iter()
+				.count() as u32,
+			past_code: <<Self as Store>::PastCode as IterableStorageMap<
+				(ParaId, T::BlockNumber),
+				ValidationCode,
+			>>::iter().count() as u32,
+			future_code: <FutureCode as IterableStorageMap<ParaId, ValidationCode>>::iter()
+				.count() as u32,
+			heads: <Heads as IterableStorageMap<ParaId, HeadData>>::iter()
+				.count() as u32,
+			past_code_meta: <<Self as Store>::PastCodeMeta as IterableStorageMap<
+				ParaId,
+				ParaPastCodeMeta<T::BlockNumber>,
+			>>::iter().count() as u32,
+		}
+	}
+
+	/// Take a snapshot of the storage of paras, to be compared against a later one with
+	/// `ParasSnapshot::diff`.
+	#[cfg(feature = "std")]
+	pub fn state_snapshot() -> ParasSnapshot<T::Hash> {
+		use frame_support::IterableStorageMap;
+
+		ParasSnapshot {
+			counts: Self::storage_entry_counts(),
+			code: <Code as IterableStorageMap<ParaId, ValidationCode>>::iter()
+				.map(|(id, code)| (id, T::Hashing::hash(&code.0)))
+				.collect(),
+			heads: <Heads as IterableStorageMap<ParaId, HeadData>>::iter()
+				.map(|(id, head)| (id, T::Hashing::hash(&head.0)))
+				.collect(),
+			future_code: <FutureCode as IterableStorageMap<ParaId, ValidationCode>>::iter()
+				.map(|(id, code)| (id, T::Hashing::hash(&code.0)))
+				.collect(),
+		}
+	}
+
+	/// Get, for every registered para, its scheduling along with the hashes of its current
+	/// code and head, ordered by `ParaId`.
+	#[cfg(feature = "std")]
+	pub fn all_paras_snapshot() -> Vec<(ParaId, Scheduling, Option<T::Hash>, Option<T::Hash>)> {
+		use frame_support::IterableStorageMap;
+
+		let mut snapshot: Vec<_> = <Heads as IterableStorageMap<ParaId, HeadData>>::iter()
+			.filter_map(|(id, head)| {
+				let info = T::Registrar::para_info(id)?;
+				let code_hash = Self::parachain_code(&id).map(|code| T::Hashing::hash(&code.0));
+				Some((id, info.scheduling, code_hash, Some(T::Hashing::hash(&head.0))))
+			})
+			.collect();
+
+		snapshot.sort_by_key(|&(id, _, _, _)| id);
+		snapshot
+	}
+
+	/// Find the para with the largest current validation code, along with the size of
+	/// that code in bytes. Ties are broken in favour of the lowest `ParaId`.
+	#[cfg(feature = "std")]
+	pub fn largest_code_para() -> Option<(ParaId, u32)> {
+		use frame_support::IterableStorageMap;
+
+		<Code as IterableStorageMap<ParaId, ValidationCode>>::iter()
+			.map(|(id, code)| (id, code.0.len() as u32))
+			.max_by(|&(id_a, len_a), &(id_b, len_b)| len_a.cmp(&len_b).then(id_b.cmp(&id_a)))
+	}
+
+	/// The earliest block number of any code which is still retained. For a para with
+	/// retained past code, this is the block at which its oldest retained code was replaced.
+	/// Otherwise, it is the block from which its current code, or that held aside while it
+	/// is paused, took effect.
+	#[cfg(feature = "std")]
+	pub fn earliest_retained_height() -> Option<T::BlockNumber> {
+		use frame_support::IterableStorageMap;
+
+		let past = <<Self as Store>::PastCodeMeta as IterableStorageMap<
+			ParaId,
+			ParaPastCodeMeta<T::BlockNumber>,
+		>>::iter().filter_map(|(_, meta)| meta.upgrade_times.last().cloned());
+
+		let paused = <<Self as Store>::PausedCode as IterableStorageMap<ParaId, ValidationCode>>::iter();
+		let current = <Code as IterableStorageMap<ParaId, ValidationCode>>::iter()
+			.chain(paused)
+			.map(|(id, _)| (id, Self::past_code_meta(&id)))
+			.filter(|(_, meta)| meta.upgrade_times.is_empty())
+			.filter_map(|(id, meta)| meta.last_pruned.or_else(||
+				// a para without a registration block is not counted as retaining code
+				// from block zero.
+				if <Self as Store>::RegisteredAt::contains_key(id) {
+					Some(<Self as Store>::RegisteredAt::get(id))
+				} else {
+					None
+				}
+			));
+
+		past.chain(current).min()
+	}
+
+	/// The registered paras whose code was most recently changed within `window` blocks
+	/// before `now`, inclusive, ordered from the most recent change to the least recent.
+	#[cfg(feature = "std")]
+	pub fn recently_upgraded(now: T::BlockNumber, window: T::BlockNumber) -> Vec<ParaId> {
+		use frame_support::IterableStorageMap;
+
+		let since = now.saturating_sub(window);
+		let mut upgraded: Vec<_> = <<Self as Store>::PastCodeMeta as IterableStorageMap<
+			ParaId,
+			ParaPastCodeMeta<T::BlockNumber>,
+		>>::iter()
+			.filter(|(id, _)| Code::contains_key(id) || <Self as Store>::PausedCode::contains_key(id))
+			.filter_map(|(id, meta)| meta.most_recent_change().map(|at| (id, at)))
+			.filter(|&(_, at)| since <= at && at <= now)
+			.collect();
+
+		upgraded.sort_by(|&(id_a, at_a), &(id_b, at_b)| at_b.cmp(&at_a).then(id_a.cmp(&id_b)));
+		upgraded.into_iter().map(|(id, _)| id).collect()
+	}
+
+	/// Count the paras by the size of their current code. `buckets` are inclusive upper
+	/// bounds on the size in bytes, in ascending order, and each para is counted in the
+	/// first bucket its code fits in. The final count is of paras whose code is larger than
+	/// every bound.
+	#[cfg(feature = "std")]
+	pub fn code_size_histogram(buckets: &[u32]) -> Vec<u32> {
+		use frame_support::IterableStorageMap;
+
+		let mut counts = vec![0; buckets.len() + 1];
+		for (_, code) in <Code as IterableStorageMap<ParaId, ValidationCode>>::iter() {
+			let size = code.0.len() as u32;
+			let bucket = buckets.iter().position(|&bound| size <= bound).unwrap_or(buckets.len());
+			counts[bucket] += 1;
+		}
+
+		counts
+	}
+
+	/// Get the current validation code of every para, ordered by `ParaId`.
+	///
+	/// This loads every code blob in storage into memory at once, so it should only be used
+	/// off-chain, e.g. when warming up a cache of validation code.
+	#[cfg(feature = "std")]
+	pub fn all_current_code() -> Vec<(ParaId, ValidationCode)> {
+		use frame_support::IterableStorageMap;
+
+		let mut codes: Vec<_> = <Code as IterableStorageMap<ParaId, ValidationCode>>::iter()
+			.collect();
+
+		codes.sort_by_key(|&(id, _)| id);
+		codes
+	}
+
 	/// Get the currently active set of parachains.
 	pub fn active_parachains() -> Vec<(ParaId, Option<(CollatorId, Retriable)>)> {
 		T::ActiveParachains::active_paras()
@@ -1208,7 +2533,6 @@ impl<T: Trait> Module<T> {
 		let parent_hash = <system::Module<T>>::parent_hash();
 		let signing_context = Self::signing_context();
 		let localized_payload = |statement: Statement| localized_payload(statement, &signing_context);
-		let code_upgrade_delay = T::ValidationUpgradeDelay::get();
 
 		let mut validator_groups = GroupedDutyIter::new(&sorted_validators[..]);
 
@@ -1254,15 +2578,12 @@ impl<T: Trait> Module<T> {
 				perceived_relay_block_height,
 			)?;
 
-			// apply any scheduled code upgrade.
-			if let Some(expected_at) = Self::code_upgrade_schedule(&para_id) {
-				if expected_at <= perceived_relay_block_height {
-					let new_code = FutureCode::take(&para_id);
-					<Self as Store>::FutureCodeUpgrades::remove(&para_id);
-
-					Self::do_code_upgrade(para_id, perceived_relay_block_height, &new_code);
-				}
-			}
+			// note the new head, applying any scheduled code upgrade.
+			Self::try_note_new_head(
+				para_id,
+				full_candidate.head_data.clone(),
+				perceived_relay_block_height,
+			)?;
 
 			if let Some(ref new_code) = full_candidate.commitments.new_validation_code {
 				ensure!(
@@ -1273,19 +2594,12 @@ impl<T: Trait> Module<T> {
 					schedule.max_code_size >= new_code.0.len() as u32,
 					Error::<T>::ValidationCodeTooLarge,
 				);
+				ensure!(Self::code_is_acceptable(new_code), Error::<T>::NotWasm);
+				ensure!(Self::code_is_allowed(new_code), Error::<T>::CodeNotAllowed);
+				ensure!(!Self::code_is_rejected(new_code), Error::<T>::CodeRejected);
+				ensure!(!Self::is_noop_upgrade(&para_id, new_code), Error::<T>::NoOpUpgrade);
 
-				if code_upgrade_delay.is_zero() {
-					Self::do_code_upgrade(para_id, perceived_relay_block_height, new_code);
-				} else {
-					<Self as Store>::FutureCodeUpgrades::insert(
-						&para_id,
-						&(perceived_relay_block_height + code_upgrade_delay),
-					);
-					FutureCode::insert(
-						&para_id,
-						new_code,
-					);
-				}
+				Self::schedule_code_upgrade(para_id, new_code, perceived_relay_block_height);
 			}
 
 			let fees = full_candidate.commitments.fees;
@@ -1563,10 +2877,11 @@ mod tests {
 		Header,
 	};
 	use keyring::Sr25519Keyring;
+	use std::cell::RefCell;
 	use frame_support::{
-		impl_outer_origin, impl_outer_dispatch, assert_ok, assert_err, parameter_types,
-		traits::{OnInitialize, OnFinalize},
-		weights::DispatchInfo,
+		impl_outer_origin, impl_outer_dispatch, impl_outer_event, assert_ok, assert_err, parameter_types,
+		traits::{OnInitialize, OnFinalize, OnRuntimeUpgrade},
+		weights::{DispatchInfo, RuntimeDbWeight},
 	};
 	use crate::parachains;
 	use crate::registrar;
@@ -1593,6 +2908,19 @@ mod tests {
 		}
 	}
 
+	impl_outer_event! {
+		pub enum TestEvent for Test {
+			system<T>,
+			session,
+			balances<T>,
+			staking<T>,
+			offences,
+			slots<T>,
+			registrar,
+			parachains<T>,
+		}
+	}
+
 	impl_opaque_keys! {
 		pub struct TestSessionKeys {
 			pub parachain_validator: super::Module<Test>,
@@ -1606,6 +2934,10 @@ mod tests {
 		pub const MaximumBlockWeight: Weight = 4 * 1024 * 1024;
 		pub const MaximumBlockLength: u32 = 4 * 1024 * 1024;
 		pub const AvailableBlockRatio: Perbill = Perbill::from_percent(75);
+		pub const TestDbWeight: RuntimeDbWeight = RuntimeDbWeight {
+			read: 1,
+			write: 10,
+		};
 	}
 
 	impl system::Trait for Test {
@@ -1618,10 +2950,10 @@ mod tests {
 		type AccountId = u64;
 		type Lookup = IdentityLookup<u64>;
 		type Header = Header;
-		type Event = ();
+		type Event = TestEvent;
 		type BlockHashCount = BlockHashCount;
 		type MaximumBlockWeight = MaximumBlockWeight;
-		type DbWeight = ();
+		type DbWeight = TestDbWeight;
 		type BlockExecutionWeight = ();
 		type ExtrinsicBaseWeight = ();
 		type MaximumExtrinsicWeight = MaximumBlockWeight;
@@ -1662,7 +2994,7 @@ mod tests {
 	}
 
 	impl session::Trait for Test {
-		type Event = ();
+		type Event = TestEvent;
 		type ValidatorId = u64;
 		type ValidatorIdOf = staking::StashOf<Self>;
 		type ShouldEndSession = session::PeriodicSessions<Period, Offset>;
@@ -1715,7 +3047,7 @@ mod tests {
 	impl balances::Trait for Test {
 		type Balance = u128;
 		type DustRemoval = ();
-		type Event = ();
+		type Event = TestEvent;
 		type ExistentialDeposit = ExistentialDeposit;
 		type AccountStore = System;
 	}
@@ -1755,7 +3087,7 @@ mod tests {
 	impl staking::Trait for Test {
 		type RewardRemainder = ();
 		type CurrencyToVote = CurrencyToVoteHandler;
-		type Event = ();
+		type Event = TestEvent;
 		type Currency = Balances;
 		type Slash = ();
 		type Reward = ();
@@ -1786,7 +3118,7 @@ mod tests {
 	}
 
 	impl slots::Trait for Test {
-		type Event = ();
+		type Event = TestEvent;
 		type Currency = Balances;
 		type Parachains = registrar::Module<Test>;
 		type EndingPeriod = EndingPeriod;
@@ -1801,7 +3133,7 @@ mod tests {
 	}
 
 	impl registrar::Trait for Test {
-		type Event = ();
+		type Event = TestEvent;
 		type Origin = Origin;
 		type Currency = Balances;
 		type ParathreadDeposit = ParathreadDeposit;
@@ -1815,7 +3147,7 @@ mod tests {
 	}
 
 	impl offences::Trait for Test {
-		type Event = ();
+		type Event = TestEvent;
 		type IdentificationTuple = session::historical::IdentificationTuple<Self>;
 		type OnOffenceHandler = Staking;
 		type WeightSoftLimit = OffencesWeightSoftLimit;
@@ -1823,11 +3155,18 @@ mod tests {
 
 	parameter_types! {
 		pub const MaxHeadDataSize: u32 = 100;
-		pub const MaxCodeSize: u32 = 100;
+		pub MaxCodeSize: u32 = MAX_CODE_SIZE.with(|v| *v.borrow());
 
 		pub const ValidationUpgradeFrequency: BlockNumber = 10;
-		pub const ValidationUpgradeDelay: BlockNumber = 2;
-		pub const SlashPeriod: BlockNumber = 50;
+		pub ValidationUpgradeDelay: BlockNumber = VALIDATION_UPGRADE_DELAY.with(|v| *v.borrow());
+		pub SlashPeriod: BlockNumber = SLASH_PERIOD.with(|v| *v.borrow());
+		pub const MaxForcedCodeUpdates: u32 = 3;
+	}
+
+	thread_local! {
+		pub static VALIDATION_UPGRADE_DELAY: RefCell<BlockNumber> = RefCell::new(2);
+		pub static MAX_CODE_SIZE: RefCell<u32> = RefCell::new(100);
+		pub static SLASH_PERIOD: RefCell<BlockNumber> = RefCell::new(50);
 	}
 
 	// This is needed for a custom `AccountId` type which is `u64` in testing here.
@@ -1861,6 +3200,7 @@ mod tests {
 
 	impl Trait for Test {
 		type AuthorityId = test_keys::ReporterAuthorityId;
+		type Event = TestEvent;
 		type Origin = Origin;
 		type Call = Call;
 		type ParachainCurrency = Balances;
@@ -1873,6 +3213,9 @@ mod tests {
 		type ValidationUpgradeFrequency = ValidationUpgradeFrequency;
 		type ValidationUpgradeDelay = ValidationUpgradeDelay;
 		type SlashPeriod = SlashPeriod;
+		type MaxForcedCodeUpdates = MaxForcedCodeUpdates;
+		type OnCodeUnavailable = TestOnCodeUnavailable;
+		type OnBeforeCodePrune = TestOnBeforeCodePrune;
 		type Proof =
 			<Historical as KeyOwnerProofSystem<(KeyTypeId, ValidatorId)>>::Proof;
 		type IdentificationTuple =
@@ -1882,6 +3225,25 @@ mod tests {
 		type KeyOwnerProofSystem = Historical;
 	}
 
+	thread_local! {
+		pub static UNAVAILABLE_CODE_QUERIES: RefCell<Vec<(ParaId, BlockNumber)>> = RefCell::new(Vec::new());
+		pub static PRUNING_VETOED: RefCell<Vec<ParaId>> = RefCell::new(Vec::new());
+	}
+
+	pub struct TestOnCodeUnavailable;
+	impl OnCodeUnavailable<BlockNumber> for TestOnCodeUnavailable {
+		fn on_code_unavailable(id: ParaId, at: BlockNumber) {
+			UNAVAILABLE_CODE_QUERIES.with(|q| q.borrow_mut().push((id, at)));
+		}
+	}
+
+	pub struct TestOnBeforeCodePrune;
+	impl OnBeforeCodePrune<BlockNumber> for TestOnBeforeCodePrune {
+		fn may_prune(id: ParaId, _: BlockNumber) -> bool {
+			!PRUNING_VETOED.with(|v| v.borrow().contains(&id))
+		}
+	}
+
 	type Extrinsic = TestXt<Call, ()>;
 
 	impl<LocalCall> system::offchain::CreateSignedTransaction<LocalCall> for Test where
@@ -2486,6 +3848,116 @@ mod tests {
 		});
 	}
 
+	#[test]
+	fn para_lifecycle_end_to_end() {
+		let parachains = vec![
+			(5u32.into(), vec![1, 2, 3].into(), vec![1].into()),
+		];
+
+		new_test_ext(parachains.clone()).execute_with(|| {
+			let para_id = ParaId::from(99u32);
+			let code_at = |at| Parachains::parachain_code_at(&para_id, at);
+
+			run_to_block(2);
+
+			// registration.
+			assert_ok!(Registrar::register_para(
+				Origin::ROOT,
+				para_id,
+				ParaInfo{scheduling: Scheduling::Always},
+				vec![7, 8, 9].into(),
+				vec![1].into(),
+			));
+			assert_ok!(Parachains::set_heads(Origin::NONE, vec![]));
+
+			run_to_block(3);
+
+			assert_eq!(Parachains::active_parachains(), vec![(5u32.into(), None), (para_id, None)]);
+			assert_eq!(Parachains::parachain_code(&para_id), Some(vec![7, 8, 9].into()));
+			assert_eq!(Parachains::parachain_head(&para_id), Some(vec![1].into()));
+
+			// a first upgrade, applied by a new head.
+			assert_ok!(Parachains::force_schedule_code_upgrade(
+				Origin::ROOT,
+				para_id,
+				vec![10, 11].into(),
+				0,
+			));
+			assert_eq!(Parachains::code_upgrade_schedule(&para_id), Some(5));
+
+			run_to_block(5);
+			Parachains::note_new_head(para_id, vec![2].into(), 5);
+
+			assert_eq!(Parachains::parachain_code(&para_id), Some(vec![10, 11].into()));
+			assert_eq!(code_at(5), Some(vec![7, 8, 9].into()));
+			assert_eq!(code_at(6), Some(vec![10, 11].into()));
+
+			// a second upgrade, some sessions later.
+			run_to_block(18);
+			assert_ok!(Parachains::force_schedule_code_upgrade(
+				Origin::ROOT,
+				para_id,
+				vec![12, 13].into(),
+				1,
+			));
+			assert_eq!(Parachains::code_upgrade_schedule(&para_id), Some(20));
+
+			run_to_block(20);
+			Parachains::note_new_head(para_id, vec![3].into(), 20);
+
+			assert_eq!(Parachains::parachain_code(&para_id), Some(vec![12, 13].into()));
+			assert_eq!(Parachains::parachain_head(&para_id), Some(vec![3].into()));
+			assert_eq!(code_at(5), Some(vec![7, 8, 9].into()));
+			assert_eq!(code_at(20), Some(vec![10, 11].into()));
+			assert_eq!(code_at(21), Some(vec![12, 13].into()));
+			assert_eq!(Parachains::past_code_pruning_tasks(), vec![(para_id, 5), (para_id, 20)]);
+
+			// the first replaced code is pruned once its slashing window has passed.
+			run_to_block(5 + SlashPeriod::get() + 1);
+
+			assert!(<Parachains as Store>::PastCode::get(&(para_id, 5)).is_none());
+			assert!(code_at(5).is_none());
+			assert_eq!(code_at(6), Some(vec![10, 11].into()));
+			assert_eq!(code_at(20), Some(vec![10, 11].into()));
+			assert_eq!(Parachains::past_code_pruning_tasks(), vec![(para_id, 20)]);
+
+			// deregistration.
+			let deregistered_at = System::block_number();
+			assert_ok!(Registrar::deregister_para(Origin::ROOT, para_id));
+			assert_ok!(Parachains::set_heads(Origin::NONE, vec![]));
+
+			assert!(System::events().iter().any(|record|
+				record.event == TestEvent::parachains(
+					RawEvent::ParaOffboardedWithHead(para_id, BlakeTwo256::hash(&[3])),
+				)
+			));
+
+			run_to_block(deregistered_at + 1);
+
+			assert_eq!(Parachains::active_parachains(), vec![(5u32.into(), None)]);
+			assert!(Parachains::parachain_code(&para_id).is_none());
+			assert!(Parachains::parachain_head(&para_id).is_none());
+
+			// the code in use at deregistration is retained like any other replaced code.
+			assert_eq!(code_at(20), Some(vec![10, 11].into()));
+			assert_eq!(code_at(deregistered_at), Some(vec![12, 13].into()));
+
+			run_to_block(20 + SlashPeriod::get() + 1);
+
+			assert!(code_at(20).is_none());
+			assert_eq!(code_at(deregistered_at), Some(vec![12, 13].into()));
+
+			// once all of its past code is pruned, nothing of the para is left.
+			run_to_block(deregistered_at + SlashPeriod::get() + 1);
+
+			assert_eq!(Parachains::past_code_meta(&para_id), Default::default());
+			assert!(<Parachains as Store>::PastCode::get(&(para_id, 20)).is_none());
+			assert!(<Parachains as Store>::PastCode::get(&(para_id, deregistered_at)).is_none());
+			assert!(Parachains::past_code_pruning_tasks().is_empty());
+			assert!(code_at(deregistered_at).is_none());
+		});
+	}
+
 	#[test]
 	fn duty_roster_works() {
 		let parachains = vec![
@@ -2645,6 +4117,23 @@ mod tests {
 		assert_eq!(past_code.code_at(6), Some(UseCodeAt::ReplacedAt(10)));
 	}
 
+	#[test]
+	fn para_past_code_meta_single_replacement_boundary() {
+		let past_code = ParaPastCodeMeta {
+			upgrade_times: vec![10u32],
+			last_pruned: None,
+		};
+
+		// code replaced at a block is still the code used to validate that block, as only
+		// replacements strictly before `at` are skipped.
+		assert_eq!(past_code.code_at(10), Some(UseCodeAt::ReplacedAt(10)));
+		assert_eq!(past_code.code_at(11), Some(UseCodeAt::Current));
+
+		// with nothing pruned, all earlier blocks resolve to the replaced code.
+		assert_eq!(past_code.code_at(9), Some(UseCodeAt::ReplacedAt(10)));
+		assert_eq!(past_code.code_at(0), Some(UseCodeAt::ReplacedAt(10)));
+	}
+
 	#[test]
 	fn para_past_code_pruning_works_correctly() {
 		let mut past_code = ParaPastCodeMeta::default();
@@ -2685,34 +4174,218 @@ mod tests {
 	}
 
 	#[test]
-	fn para_past_code_pruning_in_initialize() {
-		let parachains = vec![
-			(0u32.into(), vec![].into(), vec![].into()),
-			(1u32.into(), vec![].into(), vec![].into()),
-		];
+	fn para_past_code_bounded_pruning_resumes_correctly() {
+		let mut past_code = ParaPastCodeMeta::default();
+		for at in &[10u32, 20, 30, 40, 50] {
+			past_code.note_replacement(*at);
+		}
 
-		new_test_ext(parachains.clone()).execute_with(|| {
-			let id = ParaId::from(0u32);
-			let at_block: BlockNumber = 10;
-			<Parachains as Store>::PastCode::insert(&(id, at_block), &ValidationCode(vec![1, 2, 3]));
-			<Parachains as Store>::PastCodePruning::put(&vec![(id, at_block)]);
+		let mut pruned = Vec::new();
 
-			{
-				let mut code_meta = Parachains::past_code_meta(&id);
-				code_meta.note_replacement(at_block);
-				<Parachains as Store>::PastCodeMeta::insert(&id, &code_meta);
-			}
+		let step = past_code.prune_up_to_bounded(50, 2).collect::<Vec<_>>();
+		assert_eq!(step, vec![10, 20]);
+		assert_eq!(past_code, ParaPastCodeMeta {
+			upgrade_times: vec![50, 40, 30],
+			last_pruned: Some(20),
+		});
+		pruned.extend(step);
 
-			let pruned_at: BlockNumber = at_block + SlashPeriod::get() + 1;
-			assert_eq!(<Parachains as Store>::PastCode::get(&(id, at_block)), Some(vec![1, 2, 3].into()));
+		let step = past_code.prune_up_to_bounded(50, 2).collect::<Vec<_>>();
+		assert_eq!(step, vec![30, 40]);
+		assert_eq!(past_code, ParaPastCodeMeta {
+			upgrade_times: vec![50],
+			last_pruned: Some(40),
+		});
+		pruned.extend(step);
 
-			run_to_block(pruned_at - 1);
-			assert_eq!(<Parachains as Store>::PastCode::get(&(id, at_block)), Some(vec![1, 2, 3].into()));
-			assert_eq!(Parachains::past_code_meta(&id).most_recent_change(), Some(at_block));
+		let step = past_code.prune_up_to_bounded(50, usize::max_value()).collect::<Vec<_>>();
+		assert_eq!(step, vec![50]);
+		assert_eq!(past_code, ParaPastCodeMeta {
+			upgrade_times: Vec::new(),
+			last_pruned: Some(50),
+		});
+		pruned.extend(step);
 
-			run_to_block(pruned_at);
-			assert!(<Parachains as Store>::PastCode::get(&(id, at_block)).is_none());
-			assert!(Parachains::past_code_meta(&id).most_recent_change().is_none());
+		// every entry was yielded exactly once, oldest first.
+		assert_eq!(pruned, vec![10, 20, 30, 40, 50]);
+
+		assert!(past_code.prune_up_to_bounded(50, 2).collect::<Vec<_>>().is_empty());
+		assert_eq!(past_code.last_pruned, Some(50));
+
+		// a bound of zero prunes nothing.
+		past_code.note_replacement(60);
+		assert!(past_code.prune_up_to_bounded(60, 0).collect::<Vec<_>>().is_empty());
+		assert_eq!(past_code, ParaPastCodeMeta {
+			upgrade_times: vec![60],
+			last_pruned: Some(50),
+		});
+	}
+
+	#[test]
+	fn para_past_code_meta_consistency() {
+		let mut past_code = ParaPastCodeMeta::default();
+		assert!(past_code.is_consistent());
+
+		past_code.note_replacement(10u32);
+		past_code.note_replacement(20);
+		assert!(past_code.is_consistent());
+
+		assert_eq!(past_code.prune_up_to(10).collect::<Vec<_>>(), vec![10]);
+		assert!(past_code.is_consistent());
+
+		assert_eq!(past_code.prune_up_to(20).collect::<Vec<_>>(), vec![20]);
+		assert!(past_code.is_consistent());
+
+		let unordered = ParaPastCodeMeta {
+			upgrade_times: vec![10u32, 20],
+			last_pruned: None,
+		};
+		assert!(!unordered.is_consistent());
+
+		let pruned_after_retained = ParaPastCodeMeta {
+			upgrade_times: vec![20u32, 10],
+			last_pruned: Some(10),
+		};
+		assert!(!pruned_after_retained.is_consistent());
+	}
+
+	#[test]
+	fn shortened_slash_period_prunes_overdue_code_once() {
+		let parachains = vec![
+			(0u32.into(), vec![1].into(), vec![1].into()),
+		];
+
+		new_test_ext(parachains.clone()).execute_with(|| {
+			let para_id = ParaId::from(0u32);
+
+			Parachains::note_past_code(para_id, 10, vec![1].into());
+			Parachains::note_past_code(para_id, 20, vec![2, 2].into());
+			Parachains::note_past_code(para_id, 30, vec![3, 3, 3].into());
+
+			run_to_block(40);
+			assert_eq!(Parachains::past_code_pruning_tasks().len(), 3);
+			assert_eq!(Parachains::retention_pressure(), 6);
+
+			// code replaced at or before block 20 is now outside of the slashing window.
+			SLASH_PERIOD.with(|v| *v.borrow_mut() = 20);
+			run_to_block(41);
+
+			assert_eq!(Parachains::past_code_pruning_tasks(), vec![(para_id, 30)]);
+			assert!(<Parachains as Store>::PastCode::get(&(para_id, 10)).is_none());
+			assert!(<Parachains as Store>::PastCode::get(&(para_id, 20)).is_none());
+			assert_eq!(Parachains::past_code_meta(&para_id), ParaPastCodeMeta {
+				upgrade_times: vec![30],
+				last_pruned: Some(20),
+			});
+			assert_eq!(Parachains::retention_pressure(), 3);
+
+			run_to_block(50);
+			assert_eq!(Parachains::past_code_pruning_tasks(), vec![(para_id, 30)]);
+
+			run_to_block(51);
+			assert!(Parachains::past_code_pruning_tasks().is_empty());
+			assert_eq!(Parachains::retention_pressure(), 0);
+		});
+	}
+
+	#[test]
+	fn para_past_code_pruning_in_initialize() {
+		let parachains = vec![
+			(0u32.into(), vec![].into(), vec![].into()),
+			(1u32.into(), vec![].into(), vec![].into()),
+		];
+
+		new_test_ext(parachains.clone()).execute_with(|| {
+			let id = ParaId::from(0u32);
+			let at_block: BlockNumber = 10;
+			<Parachains as Store>::PastCode::insert(&(id, at_block), &ValidationCode(vec![1, 2, 3]));
+			<Parachains as Store>::PastCodePruning::put(&vec![(id, at_block)]);
+
+			{
+				let mut code_meta = Parachains::past_code_meta(&id);
+				code_meta.note_replacement(at_block);
+				<Parachains as Store>::PastCodeMeta::insert(&id, &code_meta);
+			}
+
+			let pruned_at: BlockNumber = at_block + SlashPeriod::get() + 1;
+			assert_eq!(<Parachains as Store>::PastCode::get(&(id, at_block)), Some(vec![1, 2, 3].into()));
+
+			run_to_block(pruned_at - 1);
+			assert_eq!(<Parachains as Store>::PastCode::get(&(id, at_block)), Some(vec![1, 2, 3].into()));
+			assert_eq!(Parachains::past_code_meta(&id).most_recent_change(), Some(at_block));
+
+			run_to_block(pruned_at);
+			assert!(<Parachains as Store>::PastCode::get(&(id, at_block)).is_none());
+			assert!(Parachains::past_code_meta(&id).most_recent_change().is_none());
+		});
+	}
+
+	#[test]
+	fn pruning_held_back_by_hook() {
+		let parachains = vec![
+			(0u32.into(), vec![1].into(), vec![1].into()),
+			(1u32.into(), vec![2].into(), vec![2].into()),
+		];
+
+		new_test_ext(parachains.clone()).execute_with(|| {
+			let id_a = ParaId::from(0u32);
+			let id_b = ParaId::from(1u32);
+
+			run_to_block(2);
+			Parachains::do_code_upgrade(id_a, 2, &vec![3].into());
+			Parachains::do_code_upgrade(id_b, 3, &vec![4].into());
+			Parachains::do_code_upgrade(id_a, 4, &vec![5].into());
+
+			PRUNING_VETOED.with(|v| v.borrow_mut().push(id_a));
+
+			run_to_block(5 + SlashPeriod::get());
+			assert!(<Parachains as Store>::PastCode::get(&(id_b, 3)).is_none());
+			assert_eq!(<Parachains as Store>::PastCode::get(&(id_a, 2)), Some(vec![1].into()));
+			assert_eq!(<Parachains as Store>::PastCode::get(&(id_a, 4)), Some(vec![3].into()));
+			assert_eq!(Parachains::past_code_pruning_tasks(), vec![(id_a, 2), (id_a, 4)]);
+			assert_eq!(Parachains::parachain_code_at(&id_a, 2), Some(vec![1].into()));
+
+			// the held back code is pruned once the hook allows it.
+			PRUNING_VETOED.with(|v| v.borrow_mut().clear());
+
+			run_to_block(6 + SlashPeriod::get());
+			assert!(<Parachains as Store>::PastCode::get(&(id_a, 2)).is_none());
+			assert!(<Parachains as Store>::PastCode::get(&(id_a, 4)).is_none());
+			assert!(Parachains::past_code_pruning_tasks().is_empty());
+		});
+	}
+
+	#[test]
+	fn para_past_code_pruning_respects_check_interval() {
+		let parachains = vec![
+			(0u32.into(), vec![].into(), vec![].into()),
+		];
+
+		new_test_ext(parachains.clone()).execute_with(|| {
+			let id = ParaId::from(0u32);
+			let at_block: BlockNumber = 10;
+			let check_interval: BlockNumber = 5;
+			let pruned_at: BlockNumber = at_block + SlashPeriod::get() + 1;
+
+			// nothing is queued, so the queue is not scanned at all.
+			assert!(Parachains::past_code_pruning_tasks().is_empty());
+			assert!(!Parachains::do_old_code_pruning(pruned_at));
+
+			assert_ok!(Parachains::set_pruning_check_interval(Origin::ROOT, check_interval));
+			Parachains::note_past_code(id, at_block, vec![1, 2, 3].into());
+
+			// the code exits the slashing window in a block where the queue isn't scanned.
+			assert!(pruned_at % check_interval != 0);
+			let checked_at = pruned_at + (check_interval - pruned_at % check_interval);
+			assert!(checked_at - pruned_at < check_interval);
+
+			run_to_block(checked_at - 1);
+			assert_eq!(<Parachains as Store>::PastCode::get(&(id, at_block)), Some(vec![1, 2, 3].into()));
+			assert_eq!(Parachains::past_code_pruning_tasks(), vec![(id, at_block)]);
+
+			run_to_block(checked_at);
+			assert!(<Parachains as Store>::PastCode::get(&(id, at_block)).is_none());
+			assert!(Parachains::past_code_pruning_tasks().is_empty());
 		});
 	}
 
@@ -2748,6 +4421,302 @@ mod tests {
 		});
 	}
 
+	#[test]
+	fn code_substitute_overrides_code_history() {
+		let parachains = vec![
+			(0u32.into(), vec![1, 2, 3].into(), vec![1].into()),
+		];
+
+		new_test_ext(parachains.clone()).execute_with(|| {
+			let para_id = ParaId::from(0u32);
+			let substitute = ValidationCode(vec![9, 9]);
+
+			Parachains::do_code_upgrade(para_id, 10, &vec![4, 5, 6].into());
+
+			assert_ok!(Parachains::set_code_substitute(
+				Origin::ROOT,
+				para_id,
+				5,
+				Some(substitute.clone()),
+			));
+
+			assert_eq!(Parachains::parachain_code_at(&para_id, 4), Some(vec![1, 2, 3].into()));
+			assert_eq!(Parachains::parachain_code_at(&para_id, 5), Some(substitute.clone()));
+			assert_eq!(Parachains::parachain_code_at(&para_id, 6), Some(vec![1, 2, 3].into()));
+			assert_eq!(
+				Parachains::parachain_codes_at(&[(para_id, 5), (para_id, 11)]),
+				vec![Some(substitute), Some(vec![4, 5, 6].into())],
+			);
+
+			assert_err!(
+				Parachains::set_code_substitute(Origin::ROOT, para_id, 6, Some(vec![0; 101].into())),
+				Error::<Test>::ValidationCodeTooLarge,
+			);
+			assert_ok!(Parachains::force_mark_code_invalid(Origin::ROOT, BlakeTwo256::hash(&[8, 8])));
+			assert_err!(
+				Parachains::set_code_substitute(Origin::ROOT, para_id, 6, Some(vec![8, 8].into())),
+				Error::<Test>::CodeRejected,
+			);
+			assert_ok!(Parachains::set_require_wasm_magic(Origin::ROOT, true));
+			assert_err!(
+				Parachains::set_code_substitute(Origin::ROOT, para_id, 6, Some(vec![7, 7].into())),
+				Error::<Test>::NotWasm,
+			);
+			assert!(!<Parachains as Store>::CodeSubstitutes::contains_key(&para_id, &6));
+
+			assert_ok!(Parachains::set_code_substitute(Origin::ROOT, para_id, 5, None));
+			assert_eq!(Parachains::parachain_code_at(&para_id, 5), Some(vec![1, 2, 3].into()));
+		});
+	}
+
+	#[test]
+	fn parachain_codes_at_matches_individual_lookups() {
+		let parachains = vec![
+			(0u32.into(), vec![1].into(), vec![1].into()),
+			(1u32.into(), vec![2].into(), vec![2].into()),
+		];
+
+		new_test_ext(parachains.clone()).execute_with(|| {
+			let id_a = ParaId::from(0u32);
+			let id_b = ParaId::from(1u32);
+
+			run_to_block(60);
+			Parachains::do_code_upgrade(id_a, 5, &vec![3].into());
+			Parachains::do_code_upgrade(id_a, 20, &vec![4].into());
+			Parachains::do_code_upgrade(id_b, 30, &vec![5].into());
+			<Parachains as Store>::PastCodeMeta::mutate(&id_a, |meta| meta.last_pruned = Some(2));
+
+			// past, current, future and pruned code, as well as an unknown para.
+			let queries = vec![
+				(id_a, 1),
+				(id_a, 4),
+				(id_a, 10),
+				(id_a, 25),
+				(id_b, 30),
+				(id_a, 100),
+				(id_b, 31),
+				(ParaId::from(2u32), 10),
+			];
+
+			let individual: Vec<_> = queries.iter()
+				.map(|(id, at)| Parachains::parachain_code_at(id, *at))
+				.collect();
+
+			assert_eq!(individual, vec![
+				None,
+				Some(vec![1].into()),
+				Some(vec![3].into()),
+				Some(vec![4].into()),
+				Some(vec![2].into()),
+				Some(vec![4].into()),
+				Some(vec![5].into()),
+				None,
+			]);
+			assert_eq!(Parachains::parachain_codes_at(&queries), individual);
+		});
+	}
+
+	#[test]
+	fn codes_for_contexts_deduplicates_code() {
+		let parachains = vec![
+			(0u32.into(), vec![1].into(), vec![1].into()),
+			(1u32.into(), vec![1].into(), vec![2].into()),
+		];
+
+		new_test_ext(parachains.clone()).execute_with(|| {
+			let id_a = ParaId::from(0u32);
+			let id_b = ParaId::from(1u32);
+
+			run_to_block(2);
+			Parachains::do_code_upgrade(id_a, 5, &vec![2].into());
+
+			let requests = vec![
+				(id_a, 3),
+				(id_b, 3),
+				(id_a, 6),
+				(id_b, 10),
+				(id_a, 4),
+				(ParaId::from(2u32), 3),
+			];
+
+			assert_eq!(Parachains::codes_for_contexts(&requests), vec![
+				(BlakeTwo256::hash(&[1]), vec![1].into()),
+				(BlakeTwo256::hash(&[2]), vec![2].into()),
+			]);
+		});
+	}
+
+	#[test]
+	fn past_code_pruning_keyed_by_replacement_block() {
+		let parachains = vec![
+			(0u32.into(), vec![1, 2, 3].into(), vec![1].into()),
+		];
+
+		new_test_ext(parachains.clone()).execute_with(|| {
+			let para_id = ParaId::from(0u32);
+
+			run_to_block(2);
+			Parachains::schedule_code_upgrade(para_id, &vec![4, 5, 6].into(), 1);
+
+			// the candidate applying the upgrade is included in block 5, in the context of
+			// its parent. the replacement is noted at the latter.
+			run_to_block(5);
+			Parachains::note_new_head(para_id, vec![2].into(), 4);
+
+			assert_eq!(Parachains::past_code_pruning_tasks(), vec![(para_id, 4)]);
+			assert_eq!(Parachains::past_code_meta(&para_id).upgrade_times, vec![4]);
+
+			// the replaced code is kept for the full slash period after the inclusion block.
+			let included_at = 5;
+			run_to_block(included_at + SlashPeriod::get() - 1);
+			assert_eq!(<Parachains as Store>::PastCode::get(&(para_id, 4)), Some(vec![1, 2, 3].into()));
+			assert_eq!(Parachains::parachain_code_at(&para_id, 4), Some(vec![1, 2, 3].into()));
+
+			run_to_block(included_at + SlashPeriod::get());
+			assert!(<Parachains as Store>::PastCode::get(&(para_id, 4)).is_none());
+			assert!(Parachains::parachain_code_at(&para_id, 4).is_none());
+			assert!(Parachains::past_code_pruning_tasks().is_empty());
+		});
+	}
+
+	#[test]
+	fn replacements_in_one_block_retain_first_replaced_code() {
+		let parachains = vec![
+			(0u32.into(), vec![1, 2, 3].into(), vec![1].into()),
+		];
+
+		new_test_ext(parachains.clone()).execute_with(|| {
+			let para_id = ParaId::from(0u32);
+
+			run_to_block(5);
+
+			Parachains::do_code_upgrade(para_id, 5, &vec![4, 5, 6].into());
+			Parachains::do_code_upgrade(para_id, 5, &vec![7, 8, 9].into());
+
+			// the intermediate code was never used, and does not replace the original code.
+			assert_eq!(Parachains::parachain_code_at(&para_id, 5), Some(vec![1, 2, 3].into()));
+			assert_eq!(Parachains::parachain_code_at(&para_id, 6), Some(vec![7, 8, 9].into()));
+			assert_eq!(Parachains::past_code_meta(&para_id).upgrade_times, vec![5]);
+			assert_eq!(Parachains::past_code_pruning_tasks(), vec![(para_id, 5)]);
+			assert_eq!(Parachains::retention_pressure(), 3);
+
+			// offboarding in the same block keeps the original code too.
+			Parachains::cleanup_para(para_id);
+			assert_eq!(Parachains::parachain_code_at(&para_id, 5), Some(vec![1, 2, 3].into()));
+			assert_eq!(Parachains::past_code_pruning_tasks(), vec![(para_id, 5)]);
+		});
+	}
+
+	#[test]
+	fn retention_pressure_tracks_retained_past_code() {
+		let parachains = vec![
+			(0u32.into(), vec![1; 10].into(), vec![1].into()),
+			(1u32.into(), vec![2; 20].into(), vec![2].into()),
+		];
+
+		new_test_ext(parachains.clone()).execute_with(|| {
+			let id_a = ParaId::from(0u32);
+			let id_b = ParaId::from(1u32);
+
+			run_to_block(2);
+			assert_eq!(Parachains::retention_pressure(), 0);
+
+			Parachains::do_code_upgrade(id_a, 2, &vec![3; 5].into());
+			Parachains::do_code_upgrade(id_b, 3, &vec![4; 5].into());
+			assert_eq!(Parachains::retention_pressure(), 30);
+
+			Parachains::do_code_upgrade(id_a, 4, &vec![5; 5].into());
+			assert_eq!(Parachains::retention_pressure(), 35);
+
+			// the past code is pruned as it exits the slashing window.
+			run_to_block(3 + SlashPeriod::get());
+			assert_eq!(Parachains::retention_pressure(), 25);
+
+			run_to_block(4 + SlashPeriod::get());
+			assert_eq!(Parachains::retention_pressure(), 5);
+
+			assert_ok!(Parachains::force_prune_para_past_code(Origin::ROOT, id_a, 4));
+			assert_eq!(Parachains::retention_pressure(), 0);
+		});
+	}
+
+	#[test]
+	fn retained_past_code_size_migration_sums_past_code_once() {
+		let parachains = vec![
+			(0u32.into(), vec![1; 10].into(), vec![1].into()),
+			(1u32.into(), vec![2; 90].into(), vec![2].into()),
+		];
+
+		new_test_ext(parachains.clone()).execute_with(|| {
+			let id_a = ParaId::from(0u32);
+			let id_b = ParaId::from(1u32);
+
+			run_to_block(2);
+			Parachains::do_code_upgrade(id_a, 2, &vec![3; 5].into());
+			Parachains::do_code_upgrade(id_b, 3, &vec![4; 5].into());
+			assert_eq!(<Parachains as Store>::StorageVersion::get(), Releases::V2);
+			assert_eq!(Parachains::retention_pressure(), 100);
+
+			// past code stored before the total was tracked.
+			<Parachains as Store>::RetainedPastCodeSize::kill();
+			<Parachains as Store>::StorageVersion::put(Releases::V1);
+
+			Parachains::on_runtime_upgrade();
+			assert_eq!(Parachains::retention_pressure(), 100);
+			assert_eq!(<Parachains as Store>::StorageVersion::get(), Releases::V2);
+
+			// the migration is not run again.
+			<Parachains as Store>::RetainedPastCodeSize::put(1);
+			Parachains::on_runtime_upgrade();
+			assert_eq!(Parachains::retention_pressure(), 1);
+		});
+	}
+
+	#[test]
+	fn para_retention_floor_is_oldest_prune_height() {
+		let parachains = vec![
+			(0u32.into(), vec![1].into(), vec![1].into()),
+		];
+
+		new_test_ext(parachains.clone()).execute_with(|| {
+			let para_id = ParaId::from(0u32);
+
+			assert!(Parachains::para_retention_floor(&para_id).is_none());
+
+			Parachains::note_past_code(para_id, 10, vec![2].into());
+			Parachains::note_past_code(para_id, 20, vec![3].into());
+
+			let floor = 10 + SlashPeriod::get() + 1;
+			assert_eq!(Parachains::para_retention_floor(&para_id), Some(floor));
+
+			run_to_block(floor);
+
+			assert!(<Parachains as Store>::PastCode::get(&(para_id, 10)).is_none());
+			assert_eq!(Parachains::para_retention_floor(&para_id), Some(20 + SlashPeriod::get() + 1));
+		});
+	}
+
+	#[test]
+	fn para_pruning_tasks_filters_by_para() {
+		let parachains = vec![
+			(0u32.into(), vec![1].into(), vec![1].into()),
+			(1u32.into(), vec![2].into(), vec![2].into()),
+		];
+
+		new_test_ext(parachains.clone()).execute_with(|| {
+			let id_a = ParaId::from(0u32);
+			let id_b = ParaId::from(1u32);
+
+			Parachains::note_past_code(id_a, 10, vec![1].into());
+			Parachains::note_past_code(id_b, 15, vec![2].into());
+			Parachains::note_past_code(id_a, 20, vec![3].into());
+
+			assert_eq!(Parachains::para_pruning_tasks(&id_a), vec![10, 20]);
+			assert_eq!(Parachains::para_pruning_tasks(&id_b), vec![15]);
+			assert!(Parachains::para_pruning_tasks(&ParaId::from(2u32)).is_empty());
+		});
+	}
+
 	#[test]
 	fn code_upgrade_applied_after_delay() {
 		let parachains = vec![
@@ -3012,6 +4981,2110 @@ mod tests {
 		});
 	}
 
+	#[test]
+	fn cleanup_para_deposits_final_head_hash() {
+		let parachains = vec![
+			(0u32.into(), vec![1, 2, 3].into(), vec![4, 5, 6].into()),
+		];
+
+		new_test_ext(parachains.clone()).execute_with(|| {
+			let para_id = ParaId::from(0);
+
+			run_to_block(2);
+
+			let head = Parachains::parachain_head(&para_id).unwrap();
+			let head_hash = BlakeTwo256::hash(&head.0);
+
+			Parachains::cleanup_para(para_id);
+
+			assert!(Parachains::parachain_head(&para_id).is_none());
+			assert!(System::events().iter().any(|record|
+				record.event == TestEvent::parachains(RawEvent::ParaOffboardedWithHead(para_id, head_hash))
+			));
+		});
+	}
+
+	#[test]
+	fn cleanup_para_removes_all_para_state() {
+		new_test_ext(vec![]).execute_with(|| {
+			let id_a = ParaId::from(1u32);
+			let id_b = ParaId::from(2u32);
+
+			run_to_block(2);
+			Parachains::initialize_para(id_a, vec![1].into(), vec![1].into());
+			Parachains::initialize_para(id_b, vec![2].into(), vec![2].into());
+
+			Parachains::do_code_upgrade(id_a, 2, &vec![3].into());
+			Parachains::schedule_code_upgrade(id_a, &vec![4].into(), 2);
+			assert_ok!(Parachains::set_code_upgrade_lock(Origin::ROOT, id_a, true));
+			assert_ok!(Parachains::set_code_substitute(Origin::ROOT, id_a, 1, Some(vec![5].into())));
+			Parachains::pause_para(id_b);
+
+			run_to_block(3);
+			Parachains::cleanup_para(id_a);
+			Parachains::cleanup_para(id_b);
+
+			for id in &[id_a, id_b] {
+				assert!(!Code::contains_key(id));
+				assert!(!Heads::contains_key(id));
+				assert!(!<Parachains as Store>::FutureCodeUpgrades::contains_key(id));
+				assert!(!<Parachains as Store>::FutureCode::contains_key(id));
+				assert!(!<Parachains as Store>::PausedCode::contains_key(id));
+				assert!(!<Parachains as Store>::CodeUpgradeLocked::contains_key(id));
+				assert!(!<Parachains as Store>::UpgradeNonce::contains_key(id));
+				assert!(!<Parachains as Store>::RegisteredAt::contains_key(id));
+				assert!(!<Parachains as Store>::UpgradeCount::contains_key(id));
+			}
+			assert!(!<Parachains as Store>::CodeSubstitutes::contains_key(&id_a, &1));
+
+			// the last code of each para is retained as past code until pruned.
+			assert_eq!(<Parachains as Store>::PastCode::get(&(id_a, 3)), Some(vec![3].into()));
+			assert_eq!(<Parachains as Store>::PastCode::get(&(id_b, 3)), Some(vec![2].into()));
+			assert_eq!(Parachains::para_pruning_tasks(&id_a), vec![2, 3]);
+			assert_eq!(Parachains::para_pruning_tasks(&id_b), vec![3]);
+		});
+	}
+
+	#[test]
+	fn pause_and_resume_para_preserves_code() {
+		let parachains = vec![
+			(0u32.into(), vec![1, 2, 3].into(), vec![4, 5, 6].into()),
+		];
+
+		new_test_ext(parachains.clone()).execute_with(|| {
+			let para_id = ParaId::from(0);
+			let code: ValidationCode = vec![1, 2, 3].into();
+			let code_hash = BlakeTwo256::hash(&code.0);
+
+			run_to_block(2);
+
+			assert_ok!(Registrar::pause_para(Origin::ROOT, para_id));
+
+			assert!(Registrar::paras(&para_id).is_none());
+			assert!(Parachains::parachain_code(&para_id).is_none());
+			assert!(Parachains::past_code_meta(&para_id).most_recent_change().is_none());
+			assert_eq!(Parachains::paused_code(&para_id), Some(code.clone()));
+			assert_eq!(Parachains::parachain_head(&para_id), Some(vec![4, 5, 6].into()));
+
+			run_to_block(3);
+			assert!(Parachains::active_parachains().is_empty());
+
+			assert_ok!(Registrar::resume_para(
+				Origin::ROOT,
+				para_id,
+				ParaInfo{scheduling: Scheduling::Always},
+			));
+
+			assert!(Parachains::paused_code(&para_id).is_none());
+			let resumed = Parachains::parachain_code(&para_id).unwrap();
+			assert_eq!(BlakeTwo256::hash(&resumed.0), code_hash);
+
+			run_to_block(4);
+			assert_eq!(Parachains::active_parachains(), vec![(para_id, None)]);
+
+			assert_err!(
+				Registrar::resume_para(Origin::ROOT, 1u32.into(), ParaInfo{scheduling: Scheduling::Always}),
+				Error::<Test>::ParaNotPaused,
+			);
+		});
+	}
+
+	#[test]
+	fn cleanup_para_deposits_discarded_upgrade_hash() {
+		let parachains = vec![
+			(0u32.into(), vec![1, 2, 3].into(), vec![4, 5, 6].into()),
+		];
+
+		new_test_ext(parachains.clone()).execute_with(|| {
+			let para_id = ParaId::from(0);
+			let future_code: ValidationCode = vec![7, 8, 9].into();
+
+			run_to_block(2);
+
+			<Parachains as Store>::FutureCodeUpgrades::insert(&para_id, &10);
+			<Parachains as Store>::FutureCode::insert(&para_id, &future_code);
+
+			Parachains::cleanup_para(para_id);
+
+			assert!(Parachains::code_upgrade_schedule(&para_id).is_none());
+			assert!(!<Parachains as Store>::FutureCode::contains_key(&para_id));
+
+			let future_code_hash = BlakeTwo256::hash(&future_code.0);
+			assert!(System::events().iter().any(|record|
+				record.event == TestEvent::parachains(
+					RawEvent::UpgradeDiscardedOnOffboard(para_id, future_code_hash)
+				)
+			));
+		});
+	}
+
+	#[test]
+	fn export_para_code_contains_current_and_past_code() {
+		new_test_ext(vec![]).execute_with(|| {
+			let para_id = ParaId::from(99u32);
+
+			run_to_block(2);
+
+			assert_ok!(Registrar::register_para(
+				Origin::ROOT,
+				para_id,
+				ParaInfo{scheduling: Scheduling::Always},
+				vec![1, 2, 3].into(),
+				vec![1].into(),
+			));
+
+			Parachains::do_code_upgrade(para_id, 5, &vec![4, 5, 6].into());
+			<Parachains as Store>::FutureCodeUpgrades::insert(&para_id, &20);
+			<Parachains as Store>::FutureCode::insert(&para_id, &ValidationCode(vec![7, 8, 9]));
+
+			let export = Parachains::export_para_code(&para_id);
+			assert_eq!(export, ParaCodeExport {
+				current: Some(vec![4, 5, 6].into()),
+				past: vec![(5, vec![1, 2, 3].into())],
+				future: Some((20, vec![7, 8, 9].into())),
+				last_pruned: None,
+			});
+			assert_eq!(ParaCodeExport::decode(&mut &export.encode()[..]).ok(), Some(export));
+		});
+	}
+
+	// These guard the SCALE encoding of types held in storage. If one of these fails,
+	// a storage migration is likely required.
+	#[test]
+	fn para_past_code_meta_encoding_is_stable() {
+		let meta = ParaPastCodeMeta::<BlockNumber> {
+			upgrade_times: vec![10, 5],
+			last_pruned: Some(2),
+		};
+		let expected = vec![
+			8,
+			10, 0, 0, 0,
+			5, 0, 0, 0,
+			1, 2, 0, 0, 0,
+		];
+
+		assert_eq!(meta.encode(), expected);
+		assert_eq!(ParaPastCodeMeta::decode(&mut &expected[..]).ok(), Some(meta));
+
+		let empty = ParaPastCodeMeta::<BlockNumber>::default();
+		assert_eq!(empty.encode(), vec![0, 0]);
+	}
+
+	#[test]
+	fn para_code_export_encoding_is_stable() {
+		let export = ParaCodeExport::<BlockNumber> {
+			current: Some(vec![1, 2].into()),
+			past: vec![(5, vec![3].into())],
+			future: None,
+			last_pruned: Some(2),
+		};
+		let expected = vec![
+			1, 8, 1, 2,
+			4, 5, 0, 0, 0, 4, 3,
+			0,
+			1, 2, 0, 0, 0,
+		];
+
+		assert_eq!(export.encode(), expected);
+		assert_eq!(ParaCodeExport::decode(&mut &expected[..]).ok(), Some(export));
+	}
+
+	#[test]
+	fn note_new_head_without_pending_upgrade_records_head() {
+		let parachains = vec![
+			(0u32.into(), vec![1, 2, 3].into(), vec![1].into()),
+		];
+
+		new_test_ext(parachains.clone()).execute_with(|| {
+			let para_id = ParaId::from(0);
+
+			run_to_block(2);
+			assert!(Parachains::code_upgrade_schedule(&para_id).is_none());
+
+			Parachains::note_new_head(para_id, vec![9, 9].into(), 1);
+
+			assert_eq!(Parachains::parachain_head(&para_id), Some(vec![9, 9].into()));
+			assert_eq!(Parachains::parachain_code(&para_id), Some(vec![1, 2, 3].into()));
+			assert_eq!(Parachains::upgrade_count(&para_id), 0);
+		});
+	}
+
+	#[test]
+	fn try_note_new_head_rejects_future_context() {
+		let parachains = vec![
+			(0u32.into(), vec![1, 2, 3].into(), vec![1].into()),
+		];
+
+		new_test_ext(parachains.clone()).execute_with(|| {
+			let para_id = ParaId::from(0);
+
+			run_to_block(2);
+
+			<Parachains as Store>::FutureCodeUpgrades::insert(&para_id, &3);
+			<Parachains as Store>::FutureCode::insert(&para_id, &ValidationCode(vec![4, 5, 6]));
+
+			assert_err!(
+				Parachains::try_note_new_head(para_id, vec![2].into(), 3),
+				Error::<Test>::ExecutionContextInFuture,
+			);
+			assert_eq!(Parachains::parachain_head(&para_id), Some(vec![1].into()));
+			assert_eq!(Parachains::parachain_code(&para_id), Some(vec![1, 2, 3].into()));
+			assert_eq!(Parachains::code_upgrade_schedule(&para_id), Some(3));
+
+			run_to_block(3);
+
+			assert_ok!(Parachains::try_note_new_head(para_id, vec![2].into(), 3));
+			assert_eq!(Parachains::parachain_head(&para_id), Some(vec![2].into()));
+			assert_eq!(Parachains::parachain_code(&para_id), Some(vec![4, 5, 6].into()));
+		});
+	}
+
+	#[test]
+	fn total_head_bytes_tracks_head_sizes() {
+		let parachains = vec![
+			(0u32.into(), vec![1, 2, 3].into(), vec![1].into()),
+		];
+
+		new_test_ext(parachains.clone()).execute_with(|| {
+			let id_a = ParaId::from(0);
+			let id_b = ParaId::from(1);
+
+			assert_eq!(Parachains::total_head_bytes(), 1);
+
+			Parachains::note_new_head(id_a, vec![1, 2, 3, 4].into(), 0);
+			assert_eq!(Parachains::total_head_bytes(), 4);
+
+			Parachains::initialize_para(id_b, vec![4, 5, 6].into(), vec![1, 2].into());
+			assert_eq!(Parachains::total_head_bytes(), 6);
+
+			// a shrinking head.
+			Parachains::note_new_head(id_a, vec![1].into(), 0);
+			assert_eq!(Parachains::total_head_bytes(), 3);
+
+			Parachains::cleanup_para(id_b);
+			assert_eq!(Parachains::total_head_bytes(), 1);
+		});
+	}
+
+	#[test]
+	fn total_head_bytes_migration_sums_existing_heads_once() {
+		let parachains = vec![
+			(0u32.into(), vec![1, 2, 3].into(), vec![1].into()),
+			(1u32.into(), vec![4, 5, 6].into(), vec![1, 2].into()),
+		];
+
+		new_test_ext(parachains.clone()).execute_with(|| {
+			assert_eq!(<Parachains as Store>::StorageVersion::get(), Releases::V2);
+			assert_eq!(Parachains::total_head_bytes(), 3);
+
+			// heads stored before the total was tracked.
+			TotalHeadBytes::kill();
+			<Parachains as Store>::StorageVersion::put(Releases::V0);
+
+			Parachains::on_runtime_upgrade();
+			assert_eq!(Parachains::total_head_bytes(), 3);
+			assert_eq!(<Parachains as Store>::StorageVersion::get(), Releases::V2);
+
+			// the migration is not run again.
+			TotalHeadBytes::put(7);
+			Parachains::on_runtime_upgrade();
+			assert_eq!(Parachains::total_head_bytes(), 7);
+		});
+	}
+
+	#[test]
+	fn note_new_head_deposits_head_accepted() {
+		let parachains = vec![
+			(0u32.into(), vec![1, 2, 3].into(), vec![1].into()),
+		];
+
+		new_test_ext(parachains.clone()).execute_with(|| {
+			let para_id = ParaId::from(0);
+
+			run_to_block(3);
+
+			Parachains::note_new_head(para_id, vec![2].into(), 1);
+			Parachains::note_new_head(para_id, vec![3].into(), 2);
+
+			let accepted: Vec<_> = System::events().into_iter()
+				.filter_map(|record| match record.event {
+					TestEvent::parachains(event @ RawEvent::HeadAccepted(..)) => Some(event),
+					_ => None,
+				})
+				.collect();
+
+			assert_eq!(accepted, vec![
+				RawEvent::HeadAccepted(para_id, BlakeTwo256::hash(&[2]), 1),
+				RawEvent::HeadAccepted(para_id, BlakeTwo256::hash(&[3]), 2),
+			]);
+		});
+	}
+
+	#[test]
+	fn oversized_upgrade_rejected_at_application() {
+		let parachains = vec![
+			(0u32.into(), vec![1, 2, 3].into(), vec![1].into()),
+		];
+
+		new_test_ext(parachains.clone()).execute_with(|| {
+			let para_id = ParaId::from(0);
+
+			run_to_block(2);
+
+			assert_ok!(Parachains::force_schedule_code_upgrade(
+				Origin::ROOT,
+				para_id,
+				vec![4, 5, 6, 7].into(),
+				0,
+			));
+			let expected_at = Parachains::code_upgrade_schedule(&para_id).unwrap();
+
+			MAX_CODE_SIZE.with(|v| *v.borrow_mut() = 3);
+
+			Parachains::note_new_head(para_id, vec![2].into(), expected_at);
+
+			assert_eq!(Parachains::parachain_head(&para_id), Some(vec![2].into()));
+			assert_eq!(Parachains::parachain_code(&para_id), Some(vec![1, 2, 3].into()));
+			assert!(Parachains::code_upgrade_schedule(&para_id).is_none());
+			assert!(!<Parachains as Store>::FutureCode::contains_key(&para_id));
+			assert!(System::events().iter().any(|record|
+				record.event == TestEvent::parachains(RawEvent::UpgradeRejectedAtApplication(para_id))
+			));
+		});
+	}
+
+	#[test]
+	fn force_note_new_head_at_applies_due_upgrade() {
+		let parachains = vec![
+			(0u32.into(), vec![1, 2, 3].into(), vec![1].into()),
+		];
+
+		new_test_ext(parachains.clone()).execute_with(|| {
+			let para_id = ParaId::from(0);
+
+			run_to_block(10);
+
+			<Parachains as Store>::FutureCodeUpgrades::insert(&para_id, &5);
+			<Parachains as Store>::FutureCode::insert(&para_id, &ValidationCode(vec![4, 5, 6]));
+
+			assert_err!(
+				Parachains::force_note_new_head_at(Origin::ROOT, para_id, vec![2].into(), 11),
+				Error::<Test>::ExecutionContextInFuture,
+			);
+
+			// the context is below the expected block of the upgrade.
+			assert_ok!(Parachains::force_note_new_head_at(Origin::ROOT, para_id, vec![2].into(), 4));
+			assert_eq!(Parachains::parachain_head(&para_id), Some(vec![2].into()));
+			assert_eq!(Parachains::parachain_code(&para_id), Some(vec![1, 2, 3].into()));
+			assert_eq!(Parachains::code_upgrade_schedule(&para_id), Some(5));
+
+			assert_ok!(Parachains::force_note_new_head_at(Origin::ROOT, para_id, vec![3].into(), 7));
+			assert_eq!(Parachains::parachain_head(&para_id), Some(vec![3].into()));
+			assert_eq!(Parachains::parachain_code(&para_id), Some(vec![4, 5, 6].into()));
+			assert!(Parachains::code_upgrade_schedule(&para_id).is_none());
+			assert_eq!(Parachains::parachain_code_at(&para_id, 7), Some(vec![1, 2, 3].into()));
+			assert_eq!(Parachains::parachain_code_at(&para_id, 8), Some(vec![4, 5, 6].into()));
+		});
+	}
+
+	#[test]
+	fn force_note_new_head_at_rejects_context_before_code_change() {
+		let parachains = vec![
+			(0u32.into(), vec![1, 2, 3].into(), vec![1].into()),
+		];
+
+		new_test_ext(parachains.clone()).execute_with(|| {
+			let para_id = ParaId::from(0);
+
+			run_to_block(2);
+
+			assert_ok!(Parachains::force_schedule_code_upgrade(
+				Origin::ROOT,
+				para_id,
+				vec![4, 5, 6].into(),
+				0,
+			));
+			let expected_at = Parachains::code_upgrade_schedule(&para_id).unwrap();
+
+			run_to_block(expected_at + 2);
+			Parachains::do_code_upgrade(para_id, expected_at + 1, &vec![7, 8, 9].into());
+
+			assert_err!(
+				Parachains::force_note_new_head_at(Origin::ROOT, para_id, vec![2].into(), expected_at),
+				Error::<Test>::ExecutionContextOutdated,
+			);
+			assert_eq!(Parachains::parachain_head(&para_id), Some(vec![1].into()));
+			assert_eq!(Parachains::parachain_code(&para_id), Some(vec![7, 8, 9].into()));
+			assert_eq!(Parachains::code_upgrade_schedule(&para_id), Some(expected_at));
+
+			assert_ok!(Parachains::force_note_new_head_at(
+				Origin::ROOT,
+				para_id,
+				vec![2].into(),
+				expected_at + 2,
+			));
+			assert_eq!(Parachains::parachain_code(&para_id), Some(vec![4, 5, 6].into()));
+			assert_eq!(Parachains::past_code_meta(&para_id).upgrade_times, vec![expected_at + 2, expected_at + 1]);
+		});
+	}
+
+	#[test]
+	fn candidate_is_included_after_code_forced_in_same_block() {
+		let parachains = vec![
+			(0u32.into(), vec![1, 2, 3].into(), vec![1].into()),
+		];
+
+		new_test_ext(parachains.clone()).execute_with(|| {
+			let para_id = ParaId::from(0);
+
+			run_to_block(3);
+
+			// as though enacted by governance in `on_initialize`.
+			assert_ok!(Parachains::force_set_current_codes(
+				Origin::ROOT,
+				vec![(para_id, vec![4, 5, 6].into())],
+			));
+			assert_eq!(Parachains::past_code_meta(&para_id).most_recent_change(), Some(3));
+
+			// the candidate is in the context of block 2, before the code change.
+			let raw_candidate = raw_candidate(para_id);
+			let head = raw_candidate.head_data.clone();
+			let mut candidate_a = make_blank_attested(raw_candidate);
+			make_attestations(&mut candidate_a);
+
+			assert_ok!(Parachains::dispatch(
+				set_heads(vec![candidate_a.clone()]),
+				Origin::NONE,
+			));
+			assert_eq!(Parachains::parachain_head(&para_id), Some(head));
+			assert_eq!(Parachains::parachain_code(&para_id), Some(vec![4, 5, 6].into()));
+
+			Parachains::on_finalize(3);
+		});
+	}
+
+	#[test]
+	fn due_upgrade_is_not_applied_in_context_before_code_change() {
+		let parachains = vec![
+			(0u32.into(), vec![1, 2, 3].into(), vec![1].into()),
+		];
+
+		new_test_ext(parachains.clone()).execute_with(|| {
+			let para_id = ParaId::from(0);
+
+			run_to_block(2);
+
+			Parachains::schedule_code_upgrade(para_id, &vec![4, 5, 6].into(), 1);
+			run_to_block(5);
+			Parachains::do_code_upgrade(para_id, 5, &vec![7, 8, 9].into());
+
+			assert_ok!(Parachains::try_note_new_head(para_id, vec![2].into(), 4));
+			assert_eq!(Parachains::parachain_head(&para_id), Some(vec![2].into()));
+			assert_eq!(Parachains::parachain_code(&para_id), Some(vec![7, 8, 9].into()));
+			assert_eq!(Parachains::code_upgrade_schedule(&para_id), Some(3));
+		});
+	}
+
+	#[test]
+	fn cleanup_para_weight_scales_with_para_count() {
+		let parachains: Vec<_> = (0u32..4)
+			.map(|i| (i.into(), vec![1, 2, 3].into(), vec![i as u8].into()))
+			.collect();
+
+		new_test_ext(parachains.clone()).execute_with(|| {
+			run_to_block(2);
+
+			let single = Parachains::cleanup_para(0u32.into());
+			assert!(single > 0);
+
+			let many: Weight = (1u32..4).map(|i| Parachains::cleanup_para(i.into())).sum();
+			assert_eq!(many, 3 * single);
+
+			// cleaning up a para with no code doesn't note any past code.
+			assert!(Parachains::cleanup_para(0u32.into()) < single);
+		});
+	}
+
+	#[test]
+	fn cleanup_para_weight_is_bounded() {
+		let parachains = vec![
+			(0u32.into(), vec![1, 2, 3].into(), vec![1].into()),
+		];
+
+		new_test_ext(parachains.clone()).execute_with(|| {
+			let para_id = ParaId::from(0u32);
+
+			run_to_block(2);
+
+			assert_ok!(Parachains::force_schedule_code_upgrade(
+				Origin::ROOT,
+				para_id,
+				vec![4, 5, 6].into(),
+				0,
+			));
+
+			assert_eq!(Parachains::cleanup_para(para_id), Parachains::max_cleanup_para_weight());
+		});
+	}
+
+	#[test]
+	fn on_code_unavailable_fires_for_pruned_code() {
+		let parachains = vec![
+			(0u32.into(), vec![1, 2, 3].into(), vec![1].into()),
+		];
+
+		new_test_ext(parachains.clone()).execute_with(|| {
+			let para_id = ParaId::from(0);
+
+			run_to_block(2);
+
+			Parachains::do_code_upgrade(para_id, 10, &vec![4, 5, 6].into());
+			<Parachains as Store>::PastCodeMeta::mutate(&para_id, |meta| {
+				let _ = meta.prune_up_to(10).count();
+			});
+
+			assert_eq!(Parachains::parachain_code_at(&para_id, 11), Some(vec![4, 5, 6].into()));
+			assert!(UNAVAILABLE_CODE_QUERIES.with(|q| q.borrow().is_empty()));
+
+			assert!(Parachains::parachain_code_at(&para_id, 10).is_none());
+			assert_eq!(UNAVAILABLE_CODE_QUERIES.with(|q| q.borrow().clone()), vec![(para_id, 10)]);
+		});
+	}
+
+	#[test]
+	fn next_prune_block_is_front_of_queue() {
+		new_test_ext(vec![]).execute_with(|| {
+			let id_a = ParaId::from(0u32);
+			let id_b = ParaId::from(1u32);
+
+			assert!(Parachains::next_prune_block().is_none());
+
+			Parachains::note_past_code(id_a, 20, vec![1, 2, 3].into());
+			assert_eq!(Parachains::next_prune_block(), Some(20));
+
+			Parachains::note_past_code(id_b, 10, vec![4, 5, 6].into());
+			assert_eq!(Parachains::past_code_pruning_tasks(), vec![(id_b, 10), (id_a, 20)]);
+			assert_eq!(Parachains::next_prune_block(), Some(10));
+		});
+	}
+
+	#[test]
+	fn code_upgrade_lock_holds_back_upgrade() {
+		let parachains = vec![
+			(0u32.into(), vec![1, 2, 3].into(), vec![1].into()),
+		];
+
+		new_test_ext(parachains.clone()).execute_with(|| {
+			let para_id = ParaId::from(0);
+
+			run_to_block(10);
+
+			assert_ok!(Parachains::set_code_upgrade_lock(Origin::ROOT, para_id, true));
+			assert!(Parachains::code_upgrade_locked(&para_id));
+
+			<Parachains as Store>::FutureCodeUpgrades::insert(&para_id, &5);
+			<Parachains as Store>::FutureCode::insert(&para_id, &ValidationCode(vec![4, 5, 6]));
+
+			Parachains::note_new_head(para_id, vec![2].into(), 6);
+			assert_eq!(Parachains::parachain_head(&para_id), Some(vec![2].into()));
+			assert_eq!(Parachains::parachain_code(&para_id), Some(vec![1, 2, 3].into()));
+			assert_eq!(Parachains::code_upgrade_schedule(&para_id), Some(5));
+			assert_eq!(<Parachains as Store>::FutureCode::get(&para_id), vec![4, 5, 6].into());
+
+			assert_ok!(Parachains::set_code_upgrade_lock(Origin::ROOT, para_id, false));
+			assert!(!Parachains::code_upgrade_locked(&para_id));
+
+			Parachains::note_new_head(para_id, vec![3].into(), 7);
+			assert_eq!(Parachains::parachain_head(&para_id), Some(vec![3].into()));
+			assert_eq!(Parachains::parachain_code(&para_id), Some(vec![4, 5, 6].into()));
+			assert!(Parachains::code_upgrade_schedule(&para_id).is_none());
+		});
+	}
+
+	#[test]
+	fn locked_para_cannot_signal_code_upgrade() {
+		let parachains = vec![
+			(0u32.into(), vec![1, 2, 3].into(), vec![1].into()),
+		];
+
+		new_test_ext(parachains.clone()).execute_with(|| {
+			let para_id = ParaId::from(0);
+
+			run_to_block(10);
+
+			assert_ok!(Parachains::force_schedule_code_upgrade(
+				Origin::ROOT,
+				para_id,
+				vec![4, 5, 6].into(),
+				0,
+			));
+			assert_ok!(Parachains::set_code_upgrade_lock(Origin::ROOT, para_id, true));
+
+			// the held-back upgrade is due, but may not be replaced.
+			run_to_block(20);
+
+			let raw_candidate = raw_candidate(para_id);
+			assert!(raw_candidate.local_validation.code_upgrade_allowed.is_none());
+			let mut candidate_a = make_blank_attested(raw_candidate);
+			candidate_a.candidate.commitments.new_validation_code = Some(vec![7, 8, 9].into());
+
+			make_attestations(&mut candidate_a);
+
+			assert_err!(
+				Parachains::dispatch(
+					set_heads(vec![candidate_a.clone()]),
+					Origin::NONE,
+				),
+				Error::<Test>::DisallowedCodeUpgrade,
+			);
+			assert_eq!(Parachains::code_upgrade_schedule(&para_id), Some(12));
+			assert_eq!(<Parachains as Store>::FutureCode::get(&para_id), vec![4, 5, 6].into());
+		});
+	}
+
+	#[test]
+	fn paused_para_holds_back_upgrade() {
+		let parachains = vec![
+			(0u32.into(), vec![1, 2, 3].into(), vec![1].into()),
+		];
+
+		new_test_ext(parachains.clone()).execute_with(|| {
+			let para_id = ParaId::from(0);
+
+			run_to_block(10);
+			Parachains::pause_para(para_id);
+
+			<Parachains as Store>::FutureCodeUpgrades::insert(&para_id, &5);
+			<Parachains as Store>::FutureCode::insert(&para_id, &ValidationCode(vec![4, 5, 6]));
+
+			Parachains::note_new_head(para_id, vec![2].into(), 6);
+			assert_eq!(Parachains::parachain_head(&para_id), Some(vec![2].into()));
+			assert!(Parachains::parachain_code(&para_id).is_none());
+			assert_eq!(Parachains::paused_code(&para_id), Some(vec![1, 2, 3].into()));
+			assert_eq!(Parachains::code_upgrade_schedule(&para_id), Some(5));
+			assert_eq!(<Parachains as Store>::FutureCode::get(&para_id), vec![4, 5, 6].into());
+			assert_eq!(Parachains::upgrade_count(&para_id), 0);
+		});
+	}
+
+	#[test]
+	fn require_wasm_magic_rejects_non_wasm_code() {
+		new_test_ext(vec![]).execute_with(|| {
+			let wasm_code: ValidationCode = vec![0x00, 0x61, 0x73, 0x6d, 1, 0, 0, 0].into();
+			let other_code: ValidationCode = vec![1, 2, 3].into();
+
+			run_to_block(2);
+
+			// off by default.
+			assert!(Parachains::code_is_acceptable(&other_code));
+
+			assert_ok!(Parachains::set_require_wasm_magic(Origin::ROOT, true));
+			assert!(Parachains::code_is_acceptable(&wasm_code));
+			assert!(!Parachains::code_is_acceptable(&other_code));
+
+			assert_ok!(Registrar::register_para(
+				Origin::ROOT,
+				99u32.into(),
+				ParaInfo{scheduling: Scheduling::Always},
+				wasm_code.clone(),
+				vec![1].into(),
+			));
+			assert_eq!(Parachains::parachain_code(&ParaId::from(99u32)), Some(wasm_code));
+
+			assert_err!(
+				Registrar::register_para(
+					Origin::ROOT,
+					100u32.into(),
+					ParaInfo{scheduling: Scheduling::Always},
+					other_code,
+					vec![1].into(),
+				),
+				registrar::Error::<Test>::NotWasm,
+			);
+			assert!(Parachains::parachain_code(&ParaId::from(100u32)).is_none());
+		});
+	}
+
+	#[test]
+	fn retention_window_bound_matches_code_lookup() {
+		let parachains = vec![
+			(0u32.into(), vec![1, 2, 3].into(), vec![1].into()),
+		];
+
+		new_test_ext(parachains.clone()).execute_with(|| {
+			let para_id = ParaId::from(0);
+			let slash_period = <Test as Trait>::SlashPeriod::get();
+
+			assert!(Parachains::retention_window_bound(slash_period).is_none());
+			assert_eq!(Parachains::retention_window_bound(slash_period + 1), Some(0));
+
+			Parachains::do_code_upgrade(para_id, 10, &vec![4, 5, 6].into());
+			Parachains::do_code_upgrade(para_id, 11, &vec![7, 8, 9].into());
+
+			let now = 10 + slash_period + 1;
+			let bound = Parachains::retention_window_bound(now).unwrap();
+			assert_eq!(bound, 10);
+
+			assert!(Parachains::do_old_code_pruning(now));
+
+			// code replaced at the bound is gone, code replaced after it is retained.
+			assert!(Parachains::parachain_code_at(&para_id, bound).is_none());
+			assert_eq!(Parachains::parachain_code_at(&para_id, bound + 1), Some(vec![4, 5, 6].into()));
+			assert_eq!(Parachains::parachain_code_at(&para_id, bound + 2), Some(vec![7, 8, 9].into()));
+		});
+	}
+
+	#[test]
+	fn largest_code_para_finds_biggest_code() {
+		let parachains = vec![
+			(0u32.into(), vec![1; 3].into(), vec![1].into()),
+			(1u32.into(), vec![2; 10].into(), vec![2].into()),
+			(2u32.into(), vec![3; 5].into(), vec![3].into()),
+		];
+
+		new_test_ext(vec![]).execute_with(|| {
+			assert!(Parachains::largest_code_para().is_none());
+		});
+
+		new_test_ext(parachains.clone()).execute_with(|| {
+			run_to_block(2);
+			assert_eq!(Parachains::largest_code_para(), Some((1u32.into(), 10)));
+		});
+	}
+
+	#[test]
+	fn earliest_retained_height_is_global_minimum() {
+		let parachains = vec![
+			(0u32.into(), vec![1].into(), vec![1].into()),
+			(1u32.into(), vec![2].into(), vec![2].into()),
+		];
+
+		new_test_ext(vec![]).execute_with(|| {
+			assert!(Parachains::earliest_retained_height().is_none());
+		});
+
+		new_test_ext(parachains.clone()).execute_with(|| {
+			let id_a = ParaId::from(0u32);
+			let id_b = ParaId::from(1u32);
+
+			// paras registered at genesis have retained their code since then.
+			assert!(<Parachains as Store>::RegisteredAt::contains_key(&id_a));
+			assert_eq!(Parachains::earliest_retained_height(), Some(0));
+
+			run_to_block(2);
+			Parachains::do_code_upgrade(id_a, 5, &vec![3].into());
+			Parachains::do_code_upgrade(id_b, 8, &vec![4].into());
+			Parachains::do_code_upgrade(id_a, 10, &vec![5].into());
+			assert_eq!(Parachains::earliest_retained_height(), Some(5));
+
+			assert_ok!(Parachains::force_prune_para_past_code(Origin::ROOT, id_a, 5));
+			assert_eq!(Parachains::earliest_retained_height(), Some(8));
+
+			// a para without past code counts from when its code took effect, even while
+			// it is paused.
+			let id_c = ParaId::from(2u32);
+			Parachains::initialize_para(id_c, vec![6].into(), vec![6].into());
+			assert_eq!(Parachains::earliest_retained_height(), Some(2));
+
+			Parachains::pause_para(id_c);
+			assert_eq!(Parachains::earliest_retained_height(), Some(2));
+
+			<Parachains as Store>::RegisteredAt::remove(&id_c);
+			assert_eq!(Parachains::earliest_retained_height(), Some(8));
+		});
+	}
+
+	#[test]
+	fn recently_upgraded_is_limited_to_window() {
+		let parachains = vec![
+			(0u32.into(), vec![1].into(), vec![1].into()),
+			(1u32.into(), vec![2].into(), vec![2].into()),
+			(2u32.into(), vec![3].into(), vec![3].into()),
+		];
+
+		new_test_ext(parachains.clone()).execute_with(|| {
+			let id_a = ParaId::from(0u32);
+			let id_b = ParaId::from(1u32);
+
+			assert!(Parachains::recently_upgraded(35, 10).is_empty());
+
+			Parachains::do_code_upgrade(id_a, 10, &vec![4].into());
+			Parachains::do_code_upgrade(id_b, 30, &vec![5].into());
+
+			assert_eq!(Parachains::recently_upgraded(35, 10), vec![id_b]);
+			assert_eq!(Parachains::recently_upgraded(35, 25), vec![id_b, id_a]);
+			assert_eq!(Parachains::recently_upgraded(20, 10), vec![id_a]);
+			assert!(Parachains::recently_upgraded(35, 4).is_empty());
+		});
+	}
+
+	#[test]
+	fn code_size_histogram_counts_paras_per_bucket() {
+		let parachains = vec![
+			(0u32.into(), vec![1; 3].into(), vec![1].into()),
+			(1u32.into(), vec![2; 10].into(), vec![2].into()),
+			(2u32.into(), vec![3; 11].into(), vec![3].into()),
+			(3u32.into(), vec![4; 40].into(), vec![4].into()),
+			(4u32.into(), vec![5; 90].into(), vec![5].into()),
+		];
+
+		new_test_ext(parachains).execute_with(|| {
+			run_to_block(2);
+
+			assert_eq!(Parachains::code_size_histogram(&[10, 50]), vec![2, 2, 1]);
+			assert_eq!(Parachains::code_size_histogram(&[100]), vec![5, 0]);
+			assert_eq!(Parachains::code_size_histogram(&[]), vec![5]);
+		});
+	}
+
+	#[test]
+	fn all_current_code_is_sorted_by_id() {
+		let parachains = vec![
+			(7u32.into(), vec![7; 3].into(), vec![1].into()),
+			(2u32.into(), vec![2; 3].into(), vec![2].into()),
+			(5u32.into(), vec![5; 3].into(), vec![3].into()),
+		];
+
+		new_test_ext(parachains).execute_with(|| {
+			run_to_block(2);
+			assert_eq!(
+				Parachains::all_current_code(),
+				vec![
+					(2u32.into(), vec![2; 3].into()),
+					(5u32.into(), vec![5; 3].into()),
+					(7u32.into(), vec![7; 3].into()),
+				],
+			);
+		});
+	}
+
+	#[test]
+	fn upgrade_delay_within_slash_period_by_default() {
+		new_test_ext(vec![]).execute_with(|| {
+			assert!(Parachains::upgrade_delay_within_slash_period());
+		});
+	}
+
+	#[test]
+	#[should_panic(expected = "`ValidationUpgradeDelay` must not exceed `SlashPeriod`")]
+	fn genesis_rejects_upgrade_delay_beyond_slash_period() {
+		VALIDATION_UPGRADE_DELAY.with(|v| *v.borrow_mut() = SlashPeriod::get() + 1);
+		new_test_ext(vec![]);
+	}
+
+	#[test]
+	fn force_schedule_code_upgrade_rejects_stale_nonce() {
+		let parachains = vec![
+			(0u32.into(), vec![1, 2, 3].into(), vec![1].into()),
+		];
+
+		new_test_ext(parachains.clone()).execute_with(|| {
+			let para_id = ParaId::from(0);
+
+			run_to_block(2);
+			assert_eq!(Parachains::upgrade_nonce(&para_id), 0);
+
+			assert_ok!(Parachains::force_schedule_code_upgrade(
+				Origin::ROOT,
+				para_id,
+				vec![4, 5, 6].into(),
+				0,
+			));
+			assert_eq!(Parachains::upgrade_nonce(&para_id), 1);
+			let expected_at = 2 + ValidationUpgradeDelay::get();
+			assert_eq!(Parachains::code_upgrade_schedule(&para_id), Some(expected_at));
+
+			let new_code_hash = BlakeTwo256::hash(&[4, 5, 6]);
+			assert!(System::events().iter().any(|record|
+				record.event == TestEvent::parachains(
+					RawEvent::CodeUpgradeScheduled(para_id, expected_at, new_code_hash)
+				)
+			));
+
+			Parachains::note_new_head(para_id, vec![2].into(), 4);
+			assert_eq!(Parachains::parachain_code(&para_id), Some(vec![4, 5, 6].into()));
+
+			// replaying the same request is rejected.
+			assert_err!(
+				Parachains::force_schedule_code_upgrade(Origin::ROOT, para_id, vec![4, 5, 6].into(), 0),
+				Error::<Test>::StaleUpgrade,
+			);
+
+			assert_ok!(Parachains::force_schedule_code_upgrade(
+				Origin::ROOT,
+				para_id,
+				vec![7, 8, 9].into(),
+				1,
+			));
+			assert_eq!(Parachains::upgrade_nonce(&para_id), 2);
+		});
+	}
+
+	#[test]
+	fn force_set_future_code_keeps_upgrade_timing() {
+		let parachains = vec![
+			(0u32.into(), vec![1, 2, 3].into(), vec![1].into()),
+		];
+
+		new_test_ext(parachains.clone()).execute_with(|| {
+			let para_id = ParaId::from(0);
+
+			run_to_block(2);
+
+			assert_err!(
+				Parachains::force_set_future_code(Origin::ROOT, para_id, vec![7, 8, 9].into()),
+				Error::<Test>::NoPlannedUpgrade,
+			);
+
+			Parachains::do_code_upgrade(para_id, 1, &vec![4, 5, 6].into());
+			assert_ok!(Parachains::force_schedule_code_upgrade(
+				Origin::ROOT,
+				para_id,
+				vec![7, 8, 9].into(),
+				0,
+			));
+			let expected_at = Parachains::code_upgrade_schedule(&para_id).unwrap();
+			let past_code_meta = Parachains::past_code_meta(&para_id);
+
+			assert_ok!(Parachains::force_set_future_code(Origin::ROOT, para_id, vec![10, 11].into()));
+			assert_eq!(Parachains::code_upgrade_schedule(&para_id), Some(expected_at));
+			assert_eq!(Parachains::past_code_meta(&para_id), past_code_meta);
+
+			Parachains::note_new_head(para_id, vec![2].into(), expected_at);
+			assert_eq!(Parachains::parachain_code(&para_id), Some(vec![10, 11].into()));
+			assert_eq!(Parachains::parachain_code_at(&para_id, expected_at), Some(vec![4, 5, 6].into()));
+		});
+	}
+
+	#[test]
+	fn force_reschedule_upgrade_moves_planned_upgrade() {
+		let parachains = vec![
+			(0u32.into(), vec![1, 2, 3].into(), vec![1].into()),
+		];
+
+		new_test_ext(parachains.clone()).execute_with(|| {
+			let para_id = ParaId::from(0);
+
+			run_to_block(2);
+
+			assert_err!(
+				Parachains::force_reschedule_upgrade(Origin::ROOT, para_id, 5),
+				Error::<Test>::NoPlannedUpgrade,
+			);
+
+			assert_ok!(Parachains::force_schedule_code_upgrade(
+				Origin::ROOT,
+				para_id,
+				vec![4, 5, 6].into(),
+				0,
+			));
+			assert_eq!(Parachains::code_upgrade_schedule(&para_id), Some(4));
+
+			// later.
+			assert_ok!(Parachains::force_reschedule_upgrade(Origin::ROOT, para_id, 6));
+			run_to_block(4);
+			Parachains::note_new_head(para_id, vec![2].into(), 4);
+			assert_eq!(Parachains::parachain_code(&para_id), Some(vec![1, 2, 3].into()));
+			assert_eq!(Parachains::code_upgrade_schedule(&para_id), Some(6));
+
+			// and earlier again.
+			assert_ok!(Parachains::force_reschedule_upgrade(Origin::ROOT, para_id, 5));
+			assert_eq!(<Parachains as Store>::FutureCode::get(&para_id), vec![4, 5, 6].into());
+
+			run_to_block(5);
+			Parachains::note_new_head(para_id, vec![3].into(), 5);
+			assert_eq!(Parachains::parachain_code(&para_id), Some(vec![4, 5, 6].into()));
+			assert_eq!(Parachains::code_upgrade_schedule(&para_id), None);
+			assert_eq!(Parachains::parachain_code_at(&para_id, 5), Some(vec![1, 2, 3].into()));
+		});
+	}
+
+	#[test]
+	fn force_reschedule_upgrade_cannot_precede_last_change() {
+		let parachains = vec![
+			(0u32.into(), vec![1, 2, 3].into(), vec![1].into()),
+		];
+
+		new_test_ext(parachains.clone()).execute_with(|| {
+			let para_id = ParaId::from(0);
+
+			run_to_block(2);
+
+			Parachains::do_code_upgrade(para_id, 1, &vec![4, 5, 6].into());
+			<Parachains as Store>::FutureCodeUpgrades::insert(&para_id, &4);
+			<Parachains as Store>::FutureCode::insert(&para_id, &ValidationCode(vec![7, 8, 9]));
+
+			assert_err!(
+				Parachains::force_reschedule_upgrade(Origin::ROOT, para_id, 1),
+				Error::<Test>::DisallowedCodeUpgrade,
+			);
+			assert_ok!(Parachains::force_reschedule_upgrade(Origin::ROOT, para_id, 2));
+			assert_eq!(Parachains::code_upgrade_schedule(&para_id), Some(2));
+		});
+	}
+
+	#[test]
+	fn force_advance_pending_upgrades_applies_due_upgrades() {
+		let parachains = vec![
+			(0u32.into(), vec![1, 2, 3].into(), vec![1].into()),
+			(1u32.into(), vec![2, 3, 4].into(), vec![1].into()),
+			(2u32.into(), vec![3, 4, 5].into(), vec![1].into()),
+		];
+
+		new_test_ext(parachains.clone()).execute_with(|| {
+			let (para_a, para_b, para_c) = (ParaId::from(0), ParaId::from(1), ParaId::from(2));
+
+			run_to_block(2);
+
+			for &(id, expected_at, ref code) in &[
+				(para_a, 3, vec![4, 5, 6]),
+				(para_b, 4, vec![5, 6, 7]),
+				(para_c, 10, vec![6, 7, 8]),
+			] {
+				<Parachains as Store>::FutureCodeUpgrades::insert(&id, &expected_at);
+				<Parachains as Store>::FutureCode::insert(&id, &ValidationCode(code.clone()));
+			}
+
+			run_to_block(5);
+
+			assert_err!(
+				Parachains::force_advance_pending_upgrades(
+					Origin::ROOT,
+					vec![para_a, para_b, para_c, 3u32.into()],
+				),
+				Error::<Test>::TooManyCodeUpdates,
+			);
+
+			assert_ok!(Parachains::force_advance_pending_upgrades(
+				Origin::ROOT,
+				vec![para_a, para_b, para_c],
+			));
+
+			assert_eq!(Parachains::parachain_code(&para_a), Some(vec![4, 5, 6].into()));
+			assert_eq!(Parachains::parachain_code(&para_b), Some(vec![5, 6, 7].into()));
+			assert_eq!(Parachains::past_code_meta(&para_a).most_recent_change(), Some(5));
+			assert_eq!(Parachains::past_code_meta(&para_b).most_recent_change(), Some(5));
+			assert_eq!(Parachains::parachain_head(&para_a), Some(vec![1].into()));
+
+			// the upgrade which is not yet due is left pending.
+			assert_eq!(Parachains::parachain_code(&para_c), Some(vec![3, 4, 5].into()));
+			assert_eq!(Parachains::code_upgrade_schedule(&para_c), Some(10));
+		});
+	}
+
+	#[test]
+	fn force_cancel_upgrade_discards_planned_upgrade() {
+		let parachains = vec![
+			(0u32.into(), vec![1, 2, 3].into(), vec![1].into()),
+		];
+
+		new_test_ext(parachains.clone()).execute_with(|| {
+			let para_id = ParaId::from(0);
+
+			run_to_block(2);
+
+			assert_ok!(Parachains::force_schedule_code_upgrade(
+				Origin::ROOT,
+				para_id,
+				vec![4, 5, 6].into(),
+				0,
+			));
+			let expected_at = Parachains::code_upgrade_schedule(&para_id).unwrap();
+			assert_ok!(Parachains::set_code_upgrade_lock(Origin::ROOT, para_id, true));
+
+			assert_ok!(Parachains::force_cancel_upgrade(Origin::ROOT, para_id));
+			assert!(Parachains::code_upgrade_schedule(&para_id).is_none());
+			assert!(!<Parachains as Store>::FutureCode::contains_key(&para_id));
+			assert!(System::events().iter().any(|record|
+				record.event == TestEvent::parachains(RawEvent::UpgradeForciblyCancelled(para_id))
+			));
+
+			assert_ok!(Parachains::set_code_upgrade_lock(Origin::ROOT, para_id, false));
+			run_to_block(expected_at + 1);
+			Parachains::note_new_head(para_id, vec![2].into(), expected_at + 1);
+			assert_eq!(Parachains::parachain_code(&para_id), Some(vec![1, 2, 3].into()));
+			assert!(Parachains::past_code_meta(&para_id).most_recent_change().is_none());
+		});
+	}
+
+	#[test]
+	fn code_for_candidate_uses_code_before_pending_upgrade() {
+		let parachains = vec![
+			(0u32.into(), vec![1, 2, 3].into(), vec![1].into()),
+		];
+
+		new_test_ext(parachains.clone()).execute_with(|| {
+			let para_id = ParaId::from(0);
+
+			run_to_block(2);
+
+			Parachains::do_code_upgrade(para_id, 1, &vec![4, 5, 6].into());
+			<Parachains as Store>::FutureCodeUpgrades::insert(&para_id, &4);
+			<Parachains as Store>::FutureCode::insert(&para_id, &ValidationCode(vec![7, 8, 9]));
+
+			assert_eq!(Parachains::code_for_candidate(&para_id, 1), Some(vec![1, 2, 3].into()));
+			assert_eq!(Parachains::code_for_candidate(&para_id, 2), Some(vec![4, 5, 6].into()));
+
+			// the upgrade is due, but the candidate triggering it uses the code it replaces.
+			assert_eq!(Parachains::code_for_candidate(&para_id, 5), Some(vec![4, 5, 6].into()));
+
+			Parachains::note_new_head(para_id, vec![2].into(), 5);
+			assert_eq!(Parachains::code_for_candidate(&para_id, 5), Some(vec![4, 5, 6].into()));
+			assert_eq!(Parachains::code_for_candidate(&para_id, 6), Some(vec![7, 8, 9].into()));
+		});
+	}
+
+	#[test]
+	fn force_set_current_codes_updates_all_paras() {
+		let parachains = vec![
+			(0u32.into(), vec![1].into(), vec![1].into()),
+			(1u32.into(), vec![2].into(), vec![2].into()),
+			(2u32.into(), vec![3].into(), vec![3].into()),
+		];
+
+		new_test_ext(parachains.clone()).execute_with(|| {
+			run_to_block(5);
+
+			let updates: Vec<(ParaId, ValidationCode)> = (0u32..3)
+				.map(|i| (i.into(), vec![10 + i as u8].into()))
+				.collect();
+
+			let mut too_many = updates.clone();
+			too_many.push((3u32.into(), vec![13].into()));
+			assert_err!(
+				Parachains::force_set_current_codes(Origin::ROOT, too_many),
+				Error::<Test>::TooManyCodeUpdates,
+			);
+
+			let mut unregistered = updates.clone();
+			unregistered[2].0 = 99u32.into();
+			assert_err!(
+				Parachains::force_set_current_codes(Origin::ROOT, unregistered),
+				Error::<Test>::UnregisteredPara,
+			);
+			assert_eq!(Parachains::parachain_code(ParaId::from(0u32)), Some(vec![1].into()));
+
+			assert_ok!(Parachains::force_set_current_codes(Origin::ROOT, updates));
+
+			for i in 0u32..3 {
+				let id = ParaId::from(i);
+				assert_eq!(Parachains::parachain_code(&id), Some(vec![10 + i as u8].into()));
+				assert_eq!(Parachains::parachain_code_at(&id, 5), Some(vec![1 + i as u8].into()));
+				assert_eq!(Parachains::parachain_code_at(&id, 6), Some(vec![10 + i as u8].into()));
+			}
+			assert_eq!(Parachains::past_code_pruning_tasks().len(), 3);
+		});
+	}
+
+	#[test]
+	fn force_set_current_codes_checks_updates() {
+		let parachains = vec![
+			(0u32.into(), vec![1].into(), vec![1].into()),
+			(1u32.into(), vec![2].into(), vec![2].into()),
+		];
+
+		new_test_ext(parachains.clone()).execute_with(|| {
+			let (id_a, id_b) = (ParaId::from(0u32), ParaId::from(1u32));
+
+			run_to_block(2);
+
+			assert_err!(
+				Parachains::force_set_current_codes(
+					Origin::ROOT,
+					vec![(id_a, vec![10].into()), (id_b, vec![11].into()), (id_a, vec![12].into())],
+				),
+				Error::<Test>::DuplicateParaId,
+			);
+			assert_eq!(Parachains::parachain_code(&id_a), Some(vec![1].into()));
+
+			assert_ok!(Parachains::set_code_hash_allowlist(Origin::ROOT, vec![BlakeTwo256::hash(&[10])]));
+			assert_ok!(Parachains::set_enforce_code_allowlist(Origin::ROOT, true));
+			assert_err!(
+				Parachains::force_set_current_codes(Origin::ROOT, vec![(id_a, vec![11].into())]),
+				Error::<Test>::CodeNotAllowed,
+			);
+			assert_ok!(Parachains::set_enforce_code_allowlist(Origin::ROOT, false));
+
+			// a planned upgrade is discarded, rather than replacing the forced code later.
+			assert_ok!(Parachains::force_schedule_code_upgrade(Origin::ROOT, id_a, vec![20].into(), 0));
+			let expected_at = Parachains::code_upgrade_schedule(&id_a).unwrap();
+
+			assert_ok!(Parachains::force_set_current_codes(Origin::ROOT, vec![(id_a, vec![10].into())]));
+			assert!(Parachains::code_upgrade_schedule(&id_a).is_none());
+			assert!(!<Parachains as Store>::FutureCode::contains_key(&id_a));
+
+			run_to_block(expected_at);
+			assert_ok!(Parachains::force_note_new_head_at(Origin::ROOT, id_a, vec![2].into(), expected_at));
+			assert_eq!(Parachains::parachain_code(&id_a), Some(vec![10].into()));
+			assert_eq!(Parachains::upgrade_count(id_a), 1);
+		});
+	}
+
+	#[test]
+	fn state_snapshot_diff_reports_code_upgrade() {
+		let parachains = vec![
+			(0u32.into(), vec![1, 2, 3].into(), vec![1].into()),
+			(1u32.into(), vec![4, 5, 6].into(), vec![2].into()),
+		];
+
+		new_test_ext(parachains.clone()).execute_with(|| {
+			let para_id = ParaId::from(0u32);
+
+			run_to_block(2);
+			let before = Parachains::state_snapshot();
+			assert!(ParasSnapshot::diff(&before, &before).is_empty());
+
+			Parachains::do_code_upgrade(para_id, 2, &vec![7, 8, 9].into());
+			let after = Parachains::state_snapshot();
+
+			assert_eq!(after.code.get(&para_id), Some(&BlakeTwo256::hash(&[7, 8, 9])));
+			assert_eq!(ParasSnapshot::diff(&before, &after), vec![
+				StateChange::Code(para_id),
+				StateChange::EntryCount("past_code", 0, 1),
+				StateChange::EntryCount("past_code_meta", 0, 1),
+			]);
+		});
+	}
+
+	#[test]
+	fn storage_entry_counts_track_code_lifecycle() {
+		let parachains = vec![
+			(0u32.into(), vec![1, 2, 3].into(), vec![1].into()),
+			(1u32.into(), vec![4, 5, 6].into(), vec![2].into()),
+		];
+
+		new_test_ext(parachains.clone()).execute_with(|| {
+			let id_a = ParaId::from(0u32);
+			let id_b = ParaId::from(1u32);
+
+			run_to_block(2);
+			assert_eq!(Parachains::storage_entry_counts(), EntryCounts {
+				current_code: 2,
+				heads: 2,
+				..Default::default()
+			});
+
+			Parachains::do_code_upgrade(id_a, 5, &vec![7, 8, 9].into());
+			<Parachains as Store>::FutureCodeUpgrades::insert(&id_b, &10);
+			<Parachains as Store>::FutureCode::insert(&id_b, &ValidationCode(vec![10, 11]));
+
+			assert_eq!(Parachains::storage_entry_counts(), EntryCounts {
+				current_code: 2,
+				past_code: 1,
+				future_code: 1,
+				heads: 2,
+				past_code_meta: 1,
+			});
+
+			// the meta of a live para is kept after its past code is pruned.
+			assert!(Parachains::do_old_code_pruning(5 + SlashPeriod::get() + 1));
+			assert_eq!(Parachains::storage_entry_counts(), EntryCounts {
+				current_code: 2,
+				past_code: 0,
+				future_code: 1,
+				heads: 2,
+				past_code_meta: 1,
+			});
+
+			Parachains::cleanup_para(id_b);
+			assert_eq!(Parachains::storage_entry_counts(), EntryCounts {
+				current_code: 1,
+				past_code: 1,
+				future_code: 0,
+				heads: 1,
+				past_code_meta: 2,
+			});
+		});
+	}
+
+	#[test]
+	fn heads_changed_this_block_resets_each_block() {
+		let parachains = vec![
+			(0u32.into(), vec![1].into(), vec![1].into()),
+			(1u32.into(), vec![2].into(), vec![2].into()),
+			(2u32.into(), vec![3].into(), vec![3].into()),
+		];
+
+		new_test_ext(parachains.clone()).execute_with(|| {
+			run_to_block(2);
+			assert!(Parachains::heads_changed_this_block().is_empty());
+
+			Parachains::note_new_head(2u32.into(), vec![4].into(), 1);
+			Parachains::note_new_head(0u32.into(), vec![5].into(), 1);
+			Parachains::note_new_head(2u32.into(), vec![6].into(), 1);
+
+			assert_eq!(
+				Parachains::heads_changed_this_block(),
+				vec![ParaId::from(0u32), ParaId::from(2u32)],
+			);
+
+			run_to_block(3);
+			assert!(Parachains::heads_changed_this_block().is_empty());
+		});
+	}
+
+	#[test]
+	fn force_prune_para_past_code_prunes_early() {
+		let parachains = vec![
+			(0u32.into(), vec![1].into(), vec![1].into()),
+			(1u32.into(), vec![2].into(), vec![2].into()),
+		];
+
+		new_test_ext(parachains.clone()).execute_with(|| {
+			let id_a = ParaId::from(0u32);
+			let id_b = ParaId::from(1u32);
+
+			run_to_block(2);
+
+			Parachains::do_code_upgrade(id_a, 3, &vec![3].into());
+			Parachains::do_code_upgrade(id_b, 4, &vec![4].into());
+			Parachains::do_code_upgrade(id_a, 5, &vec![5].into());
+
+			assert_ok!(Parachains::force_prune_para_past_code(Origin::ROOT, id_a, 4));
+
+			assert!(<Parachains as Store>::PastCode::get(&(id_a, 3)).is_none());
+			assert_eq!(<Parachains as Store>::PastCode::get(&(id_a, 5)), Some(vec![3].into()));
+			assert_eq!(Parachains::past_code_meta(&id_a), ParaPastCodeMeta {
+				upgrade_times: vec![5],
+				last_pruned: Some(3),
+			});
+			assert!(Parachains::parachain_code_at(&id_a, 3).is_none());
+
+			// other paras are untouched.
+			assert_eq!(<Parachains as Store>::PastCode::get(&(id_b, 4)), Some(vec![2].into()));
+			assert_eq!(Parachains::past_code_pruning_tasks(), vec![(id_b, 4), (id_a, 5)]);
+
+			// the past code of an offboarded para is removed entirely.
+			run_to_block(6);
+			Parachains::cleanup_para(id_b);
+			assert_ok!(Parachains::force_prune_para_past_code(Origin::ROOT, id_b, 10));
+			assert!(!<Parachains as Store>::PastCodeMeta::contains_key(&id_b));
+			assert_eq!(Parachains::past_code_pruning_tasks(), vec![(id_a, 5)]);
+		});
+	}
+
+	#[test]
+	fn do_try_state_catches_dangling_pruning_task() {
+		let parachains = vec![
+			(0u32.into(), vec![1].into(), vec![1].into()),
+		];
+
+		new_test_ext(parachains.clone()).execute_with(|| {
+			let para_id = ParaId::from(0u32);
+
+			run_to_block(2);
+			Parachains::do_code_upgrade(para_id, 2, &vec![2].into());
+			assert_eq!(Parachains::do_try_state(), Ok(()));
+
+			<Parachains as Store>::PastCodePruning::mutate(|tasks| tasks.push((para_id, 3)));
+			assert_eq!(
+				Parachains::do_try_state(),
+				Err("past code pruning task has no matching `PastCodeMeta` entry"),
+			);
+		});
+	}
+
+	// a small deterministic PRNG, so that randomized tests are reproducible from their seed.
+	struct XorShift(u64);
+
+	impl XorShift {
+		fn new(seed: u64) -> Self {
+			XorShift(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1)
+		}
+
+		fn next(&mut self) -> u64 {
+			let mut x = self.0;
+			x ^= x << 13;
+			x ^= x >> 7;
+			x ^= x << 17;
+			self.0 = x;
+			x
+		}
+
+		fn below(&mut self, n: u64) -> u64 {
+			self.next() % n
+		}
+	}
+
+	// randomly schedules, applies and offboards code of a para while advancing blocks, and
+	// checks after every step that `parachain_code_at` agrees with a simple model of the
+	// code history for every block so far.
+	//
+	// set `CODE_AT_FUZZ_SEED` to re-run only the seed of a reported failure.
+	#[test]
+	fn parachain_code_at_matches_code_history_model() {
+		let seeds: Vec<u64> = match std::env::var("CODE_AT_FUZZ_SEED") {
+			Ok(seed) => vec![seed.parse().expect("`CODE_AT_FUZZ_SEED` must be a number")],
+			Err(_) => (0..16).collect(),
+		};
+
+		for seed in seeds {
+			let genesis_code: ValidationCode = vec![0, 0].into();
+			let parachains = vec![
+				(0u32.into(), genesis_code.clone(), vec![1].into()),
+			];
+
+			new_test_ext(parachains).execute_with(|| {
+				let para_id = ParaId::from(0u32);
+				let mut rng = XorShift::new(seed);
+
+				// the blocks at which the code of the para was replaced, in ascending order,
+				// along with the code which replaced it. offboarding replaces it with nothing.
+				let mut history: Vec<(BlockNumber, Option<ValidationCode>)> = Vec::new();
+				let mut next_code = 1u8;
+				let mut offboarded = false;
+
+				run_to_block(2);
+
+				for _ in 0..60 {
+					let now = System::block_number() + 1 + rng.below(3) as BlockNumber;
+					run_to_block(now);
+
+					match rng.below(8) {
+						0 | 1 | 2 if !offboarded && Parachains::code_upgrade_schedule(&para_id).is_none() => {
+							let code: ValidationCode = vec![next_code, next_code].into();
+							next_code += 1;
+							Parachains::schedule_code_upgrade(para_id, &code, now);
+						}
+						3 | 4 | 5 if !offboarded => {
+							let due = Parachains::code_upgrade_schedule(&para_id)
+								.map_or(false, |expected_at| expected_at <= now);
+							let planned = <Parachains as Store>::FutureCode::get(&para_id);
+
+							Parachains::note_new_head(para_id, vec![now as u8].into(), now);
+							if due {
+								history.push((now, Some(planned)));
+							}
+						}
+						6 if !offboarded => {
+							Parachains::cleanup_para(para_id);
+							history.push((now, None));
+							offboarded = true;
+						}
+						_ => {}
+					}
+
+					// code replaced at or before this block has been pruned.
+					let pruned_up_to = if now > SlashPeriod::get() {
+						Some(now - SlashPeriod::get() - 1)
+					} else {
+						None
+					};
+
+					for at in 1..=now {
+						let replaced_at = history.iter().map(|&(t, _)| t).find(|&t| t >= at);
+						let expected = match (replaced_at, pruned_up_to) {
+							(Some(t), Some(bound)) if t <= bound => None,
+							_ => history.iter().rev()
+								.find(|&&(t, _)| t < at)
+								.map_or(Some(genesis_code.clone()), |(_, code)| code.clone()),
+						};
+
+						assert_eq!(
+							Parachains::parachain_code_at(&para_id, at),
+							expected,
+							"seed {}: wrong code at block {} when at block {}",
+							seed,
+							at,
+							now,
+						);
+					}
+				}
+			});
+		}
+	}
+
+	#[test]
+	fn force_fix_last_pruned_restores_hidden_code() {
+		let parachains = vec![
+			(0u32.into(), vec![1, 2, 3].into(), vec![1].into()),
+		];
+
+		new_test_ext(parachains.clone()).execute_with(|| {
+			let para_id = ParaId::from(0u32);
+
+			run_to_block(5);
+			Parachains::do_code_upgrade(para_id, 5, &vec![4, 5, 6].into());
+			assert_eq!(Parachains::parachain_code_at(&para_id, 3), Some(vec![1, 2, 3].into()));
+
+			<Parachains as Store>::PastCodeMeta::mutate(&para_id, |meta| meta.last_pruned = Some(5));
+			assert!(Parachains::parachain_code_at(&para_id, 3).is_none());
+
+			assert_ok!(Parachains::force_fix_last_pruned(Origin::ROOT, para_id, None));
+
+			assert_eq!(Parachains::past_code_meta(&para_id).last_pruned, None);
+			assert_eq!(Parachains::parachain_code_at(&para_id, 3), Some(vec![1, 2, 3].into()));
+			assert_eq!(Parachains::parachain_code_at(&para_id, 6), Some(vec![4, 5, 6].into()));
+		});
+	}
+
+	#[test]
+	fn runs_allowed_code_checks_current_code_hash() {
+		let parachains = vec![
+			(0u32.into(), vec![1, 2, 3].into(), vec![1].into()),
+			(1u32.into(), vec![4, 5, 6].into(), vec![2].into()),
+		];
+
+		new_test_ext(parachains.clone()).execute_with(|| {
+			run_to_block(2);
+
+			assert!(!Parachains::runs_allowed_code(&0u32.into()));
+
+			assert_ok!(Parachains::set_code_hash_allowlist(
+				Origin::ROOT,
+				vec![BlakeTwo256::hash(&[1, 2, 3])],
+			));
+			assert!(Parachains::runs_allowed_code(&0u32.into()));
+			assert!(!Parachains::runs_allowed_code(&1u32.into()));
+			assert!(!Parachains::runs_allowed_code(&2u32.into()));
+		});
+	}
+
+	#[test]
+	fn code_and_head_meta_match_stored_blobs() {
+		let parachains = vec![
+			(0u32.into(), vec![1, 2, 3].into(), vec![4, 5].into()),
+		];
+
+		new_test_ext(parachains.clone()).execute_with(|| {
+			let para_id = ParaId::from(0u32);
+
+			run_to_block(2);
+
+			assert_eq!(
+				Parachains::current_code_meta(&para_id),
+				Some((3, BlakeTwo256::hash(&[1, 2, 3]))),
+			);
+			assert_eq!(Parachains::head_meta(&para_id), Some((2, BlakeTwo256::hash(&[4, 5]))));
+
+			let unknown = ParaId::from(1u32);
+			assert!(Parachains::current_code_meta(&unknown).is_none());
+			assert!(Parachains::head_meta(&unknown).is_none());
+		});
+	}
+
+	#[test]
+	fn upgrade_to_current_code_is_rejected() {
+		let parachains = vec![
+			(0u32.into(), vec![1, 2, 3].into(), vec![1].into()),
+		];
+
+		new_test_ext(parachains.clone()).execute_with(|| {
+			let para_id = ParaId::from(0u32);
+
+			run_to_block(2);
+
+			assert_err!(
+				Parachains::force_schedule_code_upgrade(Origin::ROOT, para_id, vec![1, 2, 3].into(), 0),
+				Error::<Test>::NoOpUpgrade,
+			);
+			assert!(Parachains::code_upgrade_schedule(&para_id).is_none());
+			assert_eq!(Parachains::upgrade_nonce(&para_id), 0);
+
+			assert_ok!(Parachains::force_schedule_code_upgrade(
+				Origin::ROOT,
+				para_id,
+				vec![4, 5, 6].into(),
+				0,
+			));
+			assert!(Parachains::code_upgrade_schedule(&para_id).is_some());
+		});
+	}
+
+	#[test]
+	fn enforced_code_allowlist_rejects_other_upgrades() {
+		let parachains = vec![
+			(0u32.into(), vec![1, 2, 3].into(), vec![1].into()),
+		];
+
+		new_test_ext(parachains.clone()).execute_with(|| {
+			let para_id = ParaId::from(0u32);
+
+			run_to_block(2);
+
+			assert_ok!(Parachains::set_code_hash_allowlist(
+				Origin::ROOT,
+				vec![BlakeTwo256::hash(&[4, 5, 6])],
+			));
+
+			// not enforced by default.
+			assert!(Parachains::code_is_allowed(&vec![7, 8, 9].into()));
+
+			assert_ok!(Parachains::set_enforce_code_allowlist(Origin::ROOT, true));
+			assert_err!(
+				Parachains::force_schedule_code_upgrade(Origin::ROOT, para_id, vec![7, 8, 9].into(), 0),
+				Error::<Test>::CodeNotAllowed,
+			);
+			assert!(Parachains::code_upgrade_schedule(&para_id).is_none());
+
+			assert_ok!(Parachains::force_schedule_code_upgrade(
+				Origin::ROOT,
+				para_id,
+				vec![4, 5, 6].into(),
+				0,
+			));
+			assert!(Parachains::code_upgrade_schedule(&para_id).is_some());
+		});
+	}
+
+	#[test]
+	fn code_marked_invalid_is_never_upgraded_to() {
+		let parachains = vec![
+			(0u32.into(), vec![1, 2, 3].into(), vec![1].into()),
+		];
+
+		new_test_ext(parachains.clone()).execute_with(|| {
+			let para_id = ParaId::from(0u32);
+
+			run_to_block(2);
+
+			assert_ok!(Parachains::force_mark_code_invalid(Origin::ROOT, BlakeTwo256::hash(&[4, 5, 6])));
+			assert_err!(
+				Parachains::force_schedule_code_upgrade(Origin::ROOT, para_id, vec![4, 5, 6].into(), 0),
+				Error::<Test>::CodeRejected,
+			);
+			assert!(Parachains::code_upgrade_schedule(&para_id).is_none());
+
+			// code marked as invalid after an upgrade to it was planned is not applied.
+			assert_ok!(Parachains::force_schedule_code_upgrade(
+				Origin::ROOT,
+				para_id,
+				vec![7, 8, 9].into(),
+				0,
+			));
+			let expected_at = Parachains::code_upgrade_schedule(&para_id).unwrap();
+			assert_ok!(Parachains::force_mark_code_invalid(Origin::ROOT, BlakeTwo256::hash(&[7, 8, 9])));
+
+			run_to_block(expected_at);
+			Parachains::note_new_head(para_id, vec![2].into(), expected_at);
+			assert_eq!(Parachains::parachain_code(&para_id), Some(vec![1, 2, 3].into()));
+			assert!(Parachains::code_upgrade_schedule(&para_id).is_none());
+			assert!(System::events().iter().any(|record|
+				record.event == TestEvent::parachains(RawEvent::UpgradeRejectedAtApplication(para_id))
+			));
+		});
+	}
+
+	#[test]
+	fn all_paras_snapshot_covers_registered_paras() {
+		let parachains = vec![
+			(0u32.into(), vec![1].into(), vec![1].into()),
+			(1u32.into(), vec![2].into(), vec![2].into()),
+		];
+
+		new_test_ext(parachains.clone()).execute_with(|| {
+			run_to_block(2);
+
+			assert_ok!(Registrar::register_para(
+				Origin::ROOT,
+				99u32.into(),
+				ParaInfo{scheduling: Scheduling::Dynamic},
+				vec![3].into(),
+				vec![4].into(),
+			));
+
+			let hash = |data: &[u8]| Some(BlakeTwo256::hash(data));
+			assert_eq!(Parachains::all_paras_snapshot(), vec![
+				(0u32.into(), Scheduling::Always, hash(&[1]), hash(&[1])),
+				(1u32.into(), Scheduling::Always, hash(&[2]), hash(&[2])),
+				(99u32.into(), Scheduling::Dynamic, hash(&[3]), hash(&[4])),
+			]);
+		});
+	}
+
+	#[test]
+	fn schedule_code_upgrade_computes_expected_block() {
+		let parachains = vec![
+			(0u32.into(), vec![1, 2, 3].into(), vec![1].into()),
+		];
+
+		new_test_ext(parachains.clone()).execute_with(|| {
+			let para_id = ParaId::from(0u32);
+
+			run_to_block(10);
+
+			let relay_parent = 7;
+			Parachains::schedule_code_upgrade(para_id, &vec![4, 5, 6].into(), relay_parent);
+
+			assert_eq!(
+				Parachains::code_upgrade_schedule(&para_id),
+				Some(relay_parent + ValidationUpgradeDelay::get()),
+			);
+			assert_eq!(
+				Parachains::expected_upgrade_at(relay_parent),
+				relay_parent + ValidationUpgradeDelay::get(),
+			);
+		});
+	}
+
+	#[test]
+	fn upgrade_parameters_reflect_configuration() {
+		let parachains = vec![
+			(0u32.into(), vec![1, 2, 3].into(), vec![1].into()),
+		];
+
+		new_test_ext(parachains.clone()).execute_with(|| {
+			let para_id = ParaId::from(0u32);
+
+			run_to_block(3);
+			assert_eq!(Parachains::upgrade_parameters(&para_id), UpgradeParameters {
+				min_delay: 2,
+				max_code_size: 100,
+				cooldown_remaining: 0,
+				locked: false,
+			});
+
+			Parachains::do_code_upgrade(para_id, 3, &vec![4, 5, 6].into());
+			assert_ok!(Parachains::set_code_upgrade_lock(Origin::ROOT, para_id, true));
+			VALIDATION_UPGRADE_DELAY.with(|v| *v.borrow_mut() = 5);
+			MAX_CODE_SIZE.with(|v| *v.borrow_mut() = 50);
+
+			assert_eq!(Parachains::upgrade_parameters(&para_id), UpgradeParameters {
+				min_delay: 5,
+				max_code_size: 50,
+				cooldown_remaining: 12,
+				locked: true,
+			});
+
+			// the cooldown ends once a candidate can signal an upgrade again.
+			run_to_block(14);
+			assert_eq!(Parachains::upgrade_parameters(&para_id).cooldown_remaining, 1);
+			assert!(Parachains::local_validation_data(&para_id, 13).unwrap().code_upgrade_allowed.is_none());
+
+			run_to_block(15);
+			assert_eq!(Parachains::upgrade_parameters(&para_id).cooldown_remaining, 0);
+			assert!(Parachains::local_validation_data(&para_id, 14).unwrap().code_upgrade_allowed.is_some());
+		});
+	}
+
+	#[test]
+	fn current_code_age_grows_with_blocks() {
+		let parachains = vec![
+			(0u32.into(), vec![1, 2, 3].into(), vec![1].into()),
+		];
+
+		new_test_ext(parachains.clone()).execute_with(|| {
+			let para_id = ParaId::from(0u32);
+			let other_id = ParaId::from(99u32);
+
+			run_to_block(4);
+
+			// genesis paras are aged from genesis.
+			assert_eq!(Parachains::current_code_age(&para_id, 4), Some(4));
+			assert!(Parachains::current_code_age(&other_id, 4).is_none());
+
+			assert_ok!(Registrar::register_para(
+				Origin::ROOT,
+				other_id,
+				ParaInfo{scheduling: Scheduling::Always},
+				vec![4, 5, 6].into(),
+				vec![1].into(),
+			));
+			assert_eq!(Parachains::current_code_age(&other_id, 6), Some(2));
+
+			Parachains::do_code_upgrade(para_id, 4, &vec![7, 8, 9].into());
+			assert_eq!(Parachains::current_code_age(&para_id, 4), Some(0));
+			assert_eq!(Parachains::current_code_age(&para_id, 10), Some(6));
+
+			// the age is unaffected by pruning of the replaced code.
+			<Parachains as Store>::PastCodeMeta::mutate(&para_id, |meta| {
+				let _ = meta.prune_up_to(4).count();
+			});
+			assert_eq!(Parachains::current_code_age(&para_id, 10), Some(6));
+			assert_eq!(Parachains::current_code_age(&para_id, 2), Some(0));
+		});
+	}
+
+	#[test]
+	fn block_number_math_saturates_at_extremes() {
+		new_test_ext(vec![]).execute_with(|| {
+			let max = BlockNumber::max_value();
+			let slash_period = SlashPeriod::get();
+
+			assert!(Parachains::retention_window_bound(0).is_none());
+			assert!(Parachains::retention_window_bound(slash_period).is_none());
+			assert_eq!(Parachains::retention_window_bound(max), Some(max - slash_period - 1));
+
+			assert_eq!(Parachains::expected_upgrade_at(0), ValidationUpgradeDelay::get());
+			assert_eq!(Parachains::expected_upgrade_at(max - 1), max);
+			assert_eq!(Parachains::expected_upgrade_at(max), max);
+
+			let mut past_code = ParaPastCodeMeta::default();
+			past_code.note_replacement(max);
+			assert_eq!(past_code.code_at(max), Some(UseCodeAt::ReplacedAt(max)));
+			assert_eq!(past_code.code_at(0), Some(UseCodeAt::ReplacedAt(max)));
+			assert_eq!(past_code.prune_up_to(max).collect::<Vec<_>>(), vec![max]);
+			assert!(past_code.code_at(max).is_none());
+		});
+	}
+
+	#[test]
+	fn saturated_upgrade_waits_for_maximum_block() {
+		let parachains = vec![
+			(0u32.into(), vec![1, 2, 3].into(), vec![1].into()),
+		];
+
+		new_test_ext(parachains.clone()).execute_with(|| {
+			let para_id = ParaId::from(0u32);
+			let max = BlockNumber::max_value();
+
+			// the delay is non-zero, so the upgrade is not applied immediately even though
+			// the expected block saturates at the relay-parent.
+			System::set_block_number(max);
+			Parachains::schedule_code_upgrade(para_id, &vec![4, 5, 6].into(), max);
+			assert_eq!(Parachains::code_upgrade_schedule(&para_id), Some(max));
+			assert_eq!(Parachains::parachain_code(&para_id), Some(vec![1, 2, 3].into()));
+
+			assert_ok!(Parachains::try_note_new_head(para_id, vec![2].into(), max - 1));
+			assert_eq!(Parachains::parachain_code(&para_id), Some(vec![1, 2, 3].into()));
+
+			assert_ok!(Parachains::try_note_new_head(para_id, vec![3].into(), max));
+			assert_eq!(Parachains::parachain_code(&para_id), Some(vec![4, 5, 6].into()));
+		});
+	}
+
+	#[test]
+	fn para_enumeration_is_ordered_by_id() {
+		new_test_ext(vec![]).execute_with(|| {
+			run_to_block(2);
+
+			for &id in &[50u32, 7, 300, 12, 99] {
+				assert_ok!(Registrar::register_para(
+					Origin::ROOT,
+					id.into(),
+					ParaInfo{scheduling: Scheduling::Always},
+					vec![1, 2, 3].into(),
+					vec![id as u8].into(),
+				));
+			}
+
+			let ids: Vec<ParaId> = Parachains::all_paras_snapshot().into_iter()
+				.map(|(id, _, _, _)| id)
+				.collect();
+			let expected: Vec<ParaId> = vec![7u32, 12, 50, 99, 300].into_iter().map(Into::into).collect();
+			assert_eq!(ids, expected);
+
+			// all code is of the same size, so the lowest id is reported.
+			assert_eq!(Parachains::largest_code_para(), Some((7u32.into(), 3)));
+		});
+	}
+
+	#[test]
+	fn distinct_code_versions_counts_unique_current_code() {
+		let parachains = vec![
+			(0u32.into(), vec![1, 2, 3].into(), vec![1].into()),
+			(1u32.into(), vec![1, 2, 3].into(), vec![2].into()),
+			(2u32.into(), vec![4, 5, 6].into(), vec![3].into()),
+		];
+
+		new_test_ext(parachains.clone()).execute_with(|| {
+			run_to_block(2);
+			assert_eq!(Parachains::distinct_code_versions(), 2);
+		});
+	}
+
+	#[test]
+	fn upgrade_count_tracks_applied_upgrades() {
+		let parachains = vec![
+			(0u32.into(), vec![1, 2, 3].into(), vec![].into()),
+		];
+
+		new_test_ext(parachains.clone()).execute_with(|| {
+			let para_id = ParaId::from(0);
+
+			run_to_block(2);
+			assert_eq!(Parachains::upgrade_count(para_id), 0);
+
+			let upgrades: [BlockNumber; 2] = [5, 20];
+			for (i, expected_at) in upgrades.iter().enumerate() {
+				<Parachains as Store>::FutureCodeUpgrades::insert(&para_id, expected_at);
+				<Parachains as Store>::FutureCode::insert(&para_id, &ValidationCode(vec![i as u8]));
+
+				// not yet due.
+				Parachains::note_new_head(para_id, vec![i as u8].into(), expected_at - 1);
+				assert_eq!(Parachains::upgrade_count(para_id), i as u32);
+
+				Parachains::note_new_head(para_id, vec![i as u8].into(), *expected_at);
+				assert_eq!(Parachains::parachain_code(&para_id), Some(vec![i as u8].into()));
+			}
+
+			assert_eq!(Parachains::upgrade_count(para_id), 2);
+
+			Parachains::cleanup_para(para_id);
+			assert_eq!(Parachains::upgrade_count(para_id), 0);
+			assert!(!<Parachains as Store>::UpgradeCount::contains_key(&para_id));
+		});
+	}
+
+	#[test]
+	fn parachain_code_at_respects_last_pruned() {
+		let parachains = vec![
+			(0u32.into(), vec![1, 2, 3].into(), vec![].into()),
+		];
+
+		new_test_ext(parachains.clone()).execute_with(|| {
+			let para_id = ParaId::from(0);
+			let old_code = ValidationCode(vec![1, 2, 3]);
+			let new_code = ValidationCode(vec![4, 5, 6]);
+			let replaced_at: BlockNumber = 3;
+
+			run_to_block(2);
+
+			<Parachains as Store>::FutureCodeUpgrades::insert(&para_id, &replaced_at);
+			<Parachains as Store>::FutureCode::insert(&para_id, &new_code);
+			Parachains::note_new_head(para_id, vec![1].into(), replaced_at);
+
+			assert_eq!(Parachains::parachain_code_at(&para_id, 1), Some(old_code.clone()));
+			assert_eq!(Parachains::parachain_code_at(&para_id, replaced_at), Some(old_code.clone()));
+			assert_eq!(Parachains::parachain_code_at(&para_id, replaced_at + 1), Some(new_code.clone()));
+
+			let pruned_at = replaced_at + SlashPeriod::get() + 1;
+			run_to_block(pruned_at - 1);
+			assert_eq!(Parachains::parachain_code_at(&para_id, replaced_at), Some(old_code));
+
+			run_to_block(pruned_at);
+			assert!(<Parachains as Store>::PastCode::get(&(para_id, replaced_at)).is_none());
+			assert_eq!(Parachains::past_code_meta(&para_id).last_pruned, Some(replaced_at));
+
+			// blocks validated under the pruned code can no longer be checked.
+			assert!(Parachains::parachain_code_at(&para_id, 1).is_none());
+			assert!(Parachains::parachain_code_at(&para_id, replaced_at).is_none());
+
+			// but recent blocks still resolve to the current code.
+			assert_eq!(Parachains::parachain_code_at(&para_id, replaced_at + 1), Some(new_code.clone()));
+			assert_eq!(Parachains::parachain_code_at(&para_id, pruned_at), Some(new_code));
+		});
+	}
+
+	#[test]
+	fn force_import_para_code_restores_export() {
+		let parachains = vec![
+			(0u32.into(), vec![1, 2, 3].into(), vec![].into()),
+		];
+
+		new_test_ext(parachains.clone()).execute_with(|| {
+			let para_id = ParaId::from(0);
+			let new_id = ParaId::from(99);
+
+			run_to_block(6);
+
+			<Parachains as Store>::FutureCodeUpgrades::insert(&para_id, &3);
+			<Parachains as Store>::FutureCode::insert(&para_id, &ValidationCode(vec![4, 5, 6]));
+			Parachains::note_new_head(para_id, vec![1].into(), 3);
+
+			// a history which has been partly pruned.
+			Parachains::do_code_upgrade(para_id, 5, &vec![10, 11].into());
+			assert_ok!(Parachains::force_prune_para_past_code(Origin::ROOT, para_id, 3));
+
+			<Parachains as Store>::FutureCodeUpgrades::insert(&para_id, &30);
+			<Parachains as Store>::FutureCode::insert(&para_id, &ValidationCode(vec![7, 8, 9]));
+
+			let export = Parachains::export_para_code(&para_id);
+			assert_eq!(export.last_pruned, Some(3));
+			let code_at = |id| (0..10).map(|at| Parachains::parachain_code_at(&id, at)).collect::<Vec<_>>();
+			let resolved = code_at(para_id);
+			assert!(resolved[..=3].iter().all(Option::is_none));
+
+			assert_ok!(Registrar::deregister_para(Origin::ROOT, para_id));
+			assert_ok!(Parachains::force_import_para_code(Origin::ROOT, new_id, export.clone()));
+
+			assert_eq!(code_at(new_id), resolved);
+			assert_eq!(Parachains::export_para_code(&new_id), export);
+			assert_eq!(Parachains::code_upgrade_schedule(&new_id), Some(30));
+			assert!(Parachains::past_code_pruning_tasks().contains(&(new_id, 5)));
+
+			// importing over existing code is not allowed.
+			assert_err!(
+				Parachains::force_import_para_code(Origin::ROOT, new_id, export),
+				Error::<Test>::ParaCodeAlreadyExists,
+			);
+		});
+	}
+
+	#[test]
+	fn force_import_para_code_validates_export() {
+		let parachains = vec![
+			(0u32.into(), vec![1, 2, 3].into(), vec![1].into()),
+		];
+
+		new_test_ext(parachains.clone()).execute_with(|| {
+			let para_id = ParaId::from(0);
+			let new_id = ParaId::from(99);
+
+			run_to_block(2);
+
+			let export = |past: Vec<(BlockNumber, ValidationCode)>| ParaCodeExport {
+				current: Some(vec![7, 8, 9].into()),
+				past,
+				future: None,
+				last_pruned: None,
+			};
+
+			for past in vec![
+				vec![(2, vec![1].into()), (1, vec![2].into())],
+				vec![(1, vec![1].into()), (1, vec![2].into())],
+			] {
+				assert_err!(
+					Parachains::force_import_para_code(Origin::ROOT, new_id, export(past)),
+					Error::<Test>::PastCodeOutOfOrder,
+				);
+			}
+
+			// past code must have been replaced after the most recently pruned code.
+			let mut pruned_after_past = export(vec![(2, vec![1].into())]);
+			pruned_after_past.last_pruned = Some(2);
+			assert_err!(
+				Parachains::force_import_para_code(Origin::ROOT, new_id, pruned_after_past),
+				Error::<Test>::PastCodeOutOfOrder,
+			);
+
+			// and a planned upgrade must be expected after the most recent code change.
+			let mut future_before_past = export(vec![(2, vec![1].into())]);
+			future_before_past.future = Some((2, vec![3].into()));
+			assert_err!(
+				Parachains::force_import_para_code(Origin::ROOT, new_id, future_before_past),
+				Error::<Test>::FutureCodeOutOfOrder,
+			);
+
+			assert_err!(
+				Parachains::force_import_para_code(
+					Origin::ROOT,
+					new_id,
+					export(vec![(1, vec![0; 101].into())]),
+				),
+				Error::<Test>::ValidationCodeTooLarge,
+			);
+
+			assert_ok!(Parachains::force_mark_code_invalid(Origin::ROOT, BlakeTwo256::hash(&[4])));
+			assert_err!(
+				Parachains::force_import_para_code(Origin::ROOT, new_id, export(vec![(1, vec![4].into())])),
+				Error::<Test>::CodeRejected,
+			);
+			assert!(Parachains::parachain_code(&new_id).is_none());
+			assert_eq!(Parachains::past_code_meta(&new_id), Default::default());
+
+			// a planned upgrade would be overwritten.
+			assert_ok!(Parachains::force_schedule_code_upgrade(
+				Origin::ROOT,
+				para_id,
+				vec![4, 5, 6].into(),
+				0,
+			));
+			<Parachains as Store>::Code::remove(&para_id);
+			assert_err!(
+				Parachains::force_import_para_code(Origin::ROOT, para_id, export(Vec::new())),
+				Error::<Test>::ParaCodeAlreadyExists,
+			);
+
+			assert_ok!(Parachains::force_import_para_code(
+				Origin::ROOT,
+				new_id,
+				export(vec![(1, vec![1].into()), (2, vec![2].into())]),
+			));
+			assert_eq!(Parachains::past_code_meta(&new_id).upgrade_times, vec![2, 1]);
+		});
+	}
+
 	#[test]
 	fn double_vote_candidate_and_valid_works() {
 		let parachains = vec![
@@ -26,6 +26,7 @@ use codec::{Encode, Decode};
 use sp_runtime::{
 	transaction_validity::{TransactionValidityError, ValidTransaction, TransactionValidity},
 	traits::{Hash as HashT, SignedExtension, DispatchInfoOf},
+	RuntimeDebug,
 };
 
 use frame_support::{
@@ -97,6 +98,7 @@ impl<T: Trait> Registrar<T::AccountId> for Module<T> {
 		initial_head_data: HeadData,
 	) -> DispatchResult {
 		ensure!(!Paras::contains_key(id), Error::<T>::ParaAlreadyExists);
+		ensure!(!<parachains::Module<T>>::is_paused(&id), Error::<T>::ParaPaused);
 		if let Scheduling::Always = info.scheduling {
 			Parachains::mutate(|parachains|
 				match parachains.binary_search(&id) {
@@ -128,6 +130,25 @@ impl<T: Trait> Registrar<T::AccountId> for Module<T> {
 	}
 }
 
+/// What happens to the planned code upgrade of a parachain when it is downgraded to a
+/// parathread.
+#[derive(Clone, Copy, Eq, PartialEq, RuntimeDebug)]
+pub enum DowngradeUpgradePolicy {
+	/// The planned upgrade is kept, and is applied as usual once due.
+	Keep,
+	/// The planned upgrade is discarded.
+	Discard,
+}
+
+impl Default for DowngradeUpgradePolicy {
+	fn default() -> Self {
+		DowngradeUpgradePolicy::Keep
+	}
+}
+
+/// The number of most recent registration request IDs which are remembered.
+const MAX_SEEN_REGISTRATION_REQUESTS: usize = 64;
+
 type BalanceOf<T> =
 	<<T as Trait>::Currency as Currency<<T as system::Trait>::AccountId>>::Balance;
 
@@ -157,6 +178,13 @@ pub trait Trait: parachains::Trait {
 
 	/// The number of rotations that you will have as grace if you miss a block.
 	type MaxRetries: Get<u32>;
+
+	/// What happens to the planned code upgrade of a parachain when it is downgraded to a
+	/// parathread.
+	type DowngradeUpgradePolicy: Get<DowngradeUpgradePolicy>;
+
+	/// The maximum number of paras which can be deregistered in a single call.
+	type MaxBulkDeregistrations: Get<u32>;
 }
 
 decl_storage! {
@@ -194,6 +222,10 @@ decl_storage! {
 
 		/// Users who have paid a parathread's deposit
 		Debtors: map hasher(twox_64_concat) ParaId => T::AccountId;
+
+		/// The IDs of the most recent requests to register a para which have been fulfilled,
+		/// oldest first. At most `MAX_SEEN_REGISTRATION_REQUESTS` are remembered.
+		SeenRegistrationRequests: Vec<T::Hash>;
 	}
 	add_extra_genesis {
 		config(parachains): Vec<(ParaId, ValidationCode, HeadData)>;
@@ -216,7 +248,9 @@ fn build<T: Trait>(config: &GenesisConfig<T>) {
 		Paras::insert(id, &primitives::parachain::PARACHAIN_INFO);
 		// no ingress -- a chain cannot be routed to until it is live.
 		<parachains::Code>::insert(&id, &code);
+		<parachains::TotalHeadBytes>::mutate(|total| *total += genesis.0.len() as u64);
 		<parachains::Heads>::insert(&id, &genesis);
+		<parachains::RegisteredAt<T>>::insert(&id, <system::Module<T>>::block_number());
 		// Save initial parachains in registrar
 		Paras::insert(id, ParaInfo { scheduling: Scheduling::Always })
 	}
@@ -249,6 +283,12 @@ decl_error! {
 		CodeTooLarge,
 		/// Invalid para head data size.
 		HeadDataTooLarge,
+		/// Para code is not a WASM blob.
+		NotWasm,
+		/// Too many paras were supplied at once.
+		TooManyParas,
+		/// The para is paused, and must be resumed rather than registered anew.
+		ParaPaused,
 	}
 }
 
@@ -277,6 +317,7 @@ decl_module! {
 				<Self as Registrar<T::AccountId>>::code_size_allowed(code.0.len() as _),
 				Error::<T>::CodeTooLarge,
 			);
+			ensure!(<parachains::Module<T>>::code_is_acceptable(&code), Error::<T>::NotWasm);
 
 			ensure!(
 				<Self as Registrar<T::AccountId>>::head_data_size_allowed(
@@ -288,13 +329,151 @@ decl_module! {
 				register_para(id, info, code, initial_head_data)
 		}
 
+		/// Register a parachain with given code, as `register_para`, unless a registration
+		/// with the same `request_id` has already been made. This allows a registration
+		/// which may be retried to be made idempotent.
+		///
+		/// Only the most recent `MAX_SEEN_REGISTRATION_REQUESTS` request IDs are remembered,
+		/// so a request is only safe to retry until that many other such registrations have
+		/// been made.
+		///
+		/// Must be called from Root origin.
+		#[weight = (5_000_000_000, DispatchClass::Operational)]
+		pub fn register_para_with_request_id(origin,
+			request_id: T::Hash,
+			#[compact] id: ParaId,
+			info: ParaInfo,
+			code: ValidationCode,
+			initial_head_data: HeadData,
+		) -> DispatchResult {
+			ensure_root(origin.clone())?;
+
+			if <SeenRegistrationRequests<T>>::get().contains(&request_id) {
+				return Ok(())
+			}
+
+			Self::register_para(origin, id, info, code, initial_head_data)?;
+			<SeenRegistrationRequests<T>>::mutate(|seen| {
+				if seen.len() >= MAX_SEEN_REGISTRATION_REQUESTS {
+					seen.remove(0);
+				}
+				seen.push(request_id);
+			});
+			Ok(())
+		}
+
 		/// Deregister a parachain with given id
-		#[weight = (10_000_000, DispatchClass::Operational)]
+		#[weight = (
+			10_000_000 + <parachains::Module<T>>::max_cleanup_para_weight(),
+			DispatchClass::Operational,
+		)]
 		pub fn deregister_para(origin, #[compact] id: ParaId) -> DispatchResult {
 			ensure_root(origin)?;
 			<Self as Registrar<T::AccountId>>::deregister_para(id)
 		}
 
+		/// Deregister a number of paras at once. Paras which are not registered are skipped.
+		///
+		/// Must be called by root.
+		#[weight = (
+			(10_000_000 + <parachains::Module<T>>::max_cleanup_para_weight()) * ids.len() as Weight,
+			DispatchClass::Operational,
+		)]
+		pub fn deregister_paras(origin, ids: Vec<ParaId>) -> DispatchResult {
+			ensure_root(origin)?;
+			ensure!(ids.len() as u32 <= T::MaxBulkDeregistrations::get(), Error::<T>::TooManyParas);
+
+			let (deregistered, skipped) = Self::do_deregister_paras(ids);
+			Self::deposit_event(Event::ParasDeregistered(deregistered, skipped));
+			Ok(())
+		}
+
+		/// Pause a para. It is deregistered, but its code and head are preserved so that it
+		/// can later be resumed with `resume_para` without re-uploading its code.
+		///
+		/// Must be called from Root origin.
+		#[weight = (10_000_000, DispatchClass::Operational)]
+		pub fn pause_para(origin, #[compact] id: ParaId) -> DispatchResult {
+			ensure_root(origin)?;
+
+			let info = Paras::take(id).ok_or(Error::<T>::InvalidChainId)?;
+			if let Scheduling::Always = info.scheduling {
+				Parachains::mutate(|parachains|
+					parachains.binary_search(&id)
+						.map(|index| parachains.remove(index))
+						.map_err(|_| Error::<T>::InvalidChainId)
+				)?;
+			}
+			Self::force_unschedule(|i| i == id);
+
+			<parachains::Module<T>>::pause_para(id);
+			Ok(())
+		}
+
+		/// Resume a paused para with the given scheduling info, re-installing the code
+		/// it had when it was paused.
+		///
+		/// Must be called from Root origin.
+		#[weight = (10_000_000, DispatchClass::Operational)]
+		pub fn resume_para(origin, #[compact] id: ParaId, info: ParaInfo) -> DispatchResult {
+			ensure_root(origin)?;
+			ensure!(!Paras::contains_key(id), Error::<T>::ParaAlreadyExists);
+
+			<parachains::Module<T>>::resume_para(id)?;
+			if let Scheduling::Always = info.scheduling {
+				Parachains::mutate(|parachains|
+					match parachains.binary_search(&id) {
+						Ok(_) => Err(Error::<T>::ParaAlreadyExists),
+						Err(idx) => {
+							parachains.insert(idx, id);
+							Ok(())
+						}
+					}
+				)?;
+			}
+			Paras::insert(id, info);
+			Ok(())
+		}
+
+		/// Change the scheduling of a registered para, moving it between the parachains and
+		/// the parathreads without deregistering it.
+		///
+		/// When a parachain is downgraded to a parathread, its planned code upgrade is kept or
+		/// discarded according to the `DowngradeUpgradePolicy`.
+		///
+		/// Must be called from Root origin.
+		#[weight = (10_000_000, DispatchClass::Operational)]
+		pub fn set_scheduling(origin, #[compact] id: ParaId, scheduling: Scheduling) -> DispatchResult {
+			ensure_root(origin)?;
+
+			let mut info = Paras::get(id).ok_or(Error::<T>::InvalidChainId)?;
+			match (&info.scheduling, &scheduling) {
+				(Scheduling::Dynamic, Scheduling::Always) => {
+					Self::force_unschedule(|i| i == id);
+					Parachains::mutate(|parachains|
+						if let Err(idx) = parachains.binary_search(&id) {
+							parachains.insert(idx, id);
+						}
+					);
+				}
+				(Scheduling::Always, Scheduling::Dynamic) => {
+					Parachains::mutate(|parachains|
+						if let Ok(idx) = parachains.binary_search(&id) {
+							parachains.remove(idx);
+						}
+					);
+					if T::DowngradeUpgradePolicy::get() == DowngradeUpgradePolicy::Discard {
+						<parachains::Module<T>>::discard_future_code(id);
+					}
+				}
+				_ => return Ok(()),
+			}
+
+			info.scheduling = scheduling;
+			Paras::insert(id, info);
+			Ok(())
+		}
+
 		/// Reset the number of parathreads that can pay to be scheduled in a single block.
 		///
 		/// - `count`: The number of parathreads.
@@ -331,6 +510,7 @@ decl_module! {
 				<Self as Registrar<T::AccountId>>::code_size_allowed(code.0.len() as _),
 				Error::<T>::CodeTooLarge,
 			);
+			ensure!(<parachains::Module<T>>::code_is_acceptable(&code), Error::<T>::NotWasm);
 
 			ensure!(
 				<Self as Registrar<T::AccountId>>::head_data_size_allowed(
@@ -371,7 +551,7 @@ decl_module! {
 		/// Ensure that before calling this that any funds you want emptied from the parathread's
 		/// account is moved out; after this it will be impossible to retrieve them (without
 		/// governance intervention).
-		#[weight = 0]
+		#[weight = <parachains::Module<T>>::max_cleanup_para_weight()]
 		fn deregister_parathread(origin) {
 			let id = parachains::ensure_parachain(<T as Trait>::Origin::from(origin))?;
 
@@ -424,6 +604,15 @@ decl_module! {
 			}
 		}
 
+		fn on_runtime_upgrade() -> Weight {
+			if Self::repair_parachains_order() {
+				sp_runtime::print("Repaired the order of registered parachains");
+				T::DbWeight::get().reads_writes(1, 1)
+			} else {
+				T::DbWeight::get().reads(1)
+			}
+		}
+
 		/// Block initializer. Clears SelectedThreads and constructs/replaces Active.
 		fn on_initialize() -> Weight {
 			let next_up = SelectedThreads::mutate(|t| {
@@ -449,7 +638,11 @@ decl_module! {
 				}
 			}
 
-			let mut paras = Parachains::get().into_iter()
+			let parachains = Parachains::get();
+			// every mutation of `Parachains` must keep it sorted and free of duplicates.
+			debug_assert!(parachains.windows(2).all(|w| w[0] < w[1]));
+
+			let mut paras = parachains.into_iter()
 				.map(|id| (id, None))
 				.chain(next_up.into_iter()
 					.map(|(para, collator)|
@@ -501,10 +694,75 @@ decl_event!{
 
 		/// The parathread of the supplied ID was de-registered.
 		ParathreadDeregistered(ParaId),
+
+		/// The first supplied paras were de-registered in bulk. The second supplied paras
+		/// were skipped, as they were not registered.
+		ParasDeregistered(Vec<ParaId>, Vec<ParaId>),
 	}
 }
 
 impl<T: Trait> Module<T> {
+	// sort and deduplicate `Parachains`, which every mutation must keep in that form.
+	// returns whether a repair was needed.
+	fn repair_parachains_order() -> bool {
+		let mut parachains = Parachains::get();
+		if parachains.windows(2).all(|w| w[0] < w[1]) {
+			return false
+		}
+
+		parachains.sort();
+		parachains.dedup();
+		Parachains::put(parachains);
+		true
+	}
+
+	// deregister each of the given paras which is registered. returns the paras which were
+	// deregistered and those which were skipped.
+	fn do_deregister_paras(ids: Vec<ParaId>) -> (Vec<ParaId>, Vec<ParaId>) {
+		ids.into_iter().partition(|&id|
+			<Self as Registrar<T::AccountId>>::deregister_para(id).is_ok()
+		)
+	}
+
+	/// Whether the given `ParaId` is in the range reserved for system paras. These can
+	/// only be registered by Root; signed registrations are always given a user ID.
+	pub fn is_system_para(id: ParaId) -> bool {
+		id.is_system()
+	}
+
+	/// Get up to `count` of the registered parachains, in ascending order of `ParaId`,
+	/// starting from the `start`-th.
+	pub fn parachains_paged(start: u32, count: u32) -> Vec<ParaId> {
+		let mut parachains = Parachains::get();
+		let start = (start as usize).min(parachains.len());
+		let end = start.saturating_add(count as usize).min(parachains.len());
+
+		parachains.truncate(end);
+		parachains.split_off(start)
+	}
+
+	/// Check the consistency of the storage of registered paras: `Parachains` must be sorted
+	/// and deduplicated, and every parachain in it must have current code and a head. This
+	/// also checks the storage of the `parachains` module.
+	#[cfg(any(feature = "try-runtime", test))]
+	pub fn do_try_state() -> Result<(), &'static str> {
+		let ids = Parachains::get();
+		if !ids.windows(2).all(|w| w[0] < w[1]) {
+			return Err("`Parachains` is not sorted and deduplicated");
+		}
+
+		for id in &ids {
+			if <parachains::Module<T>>::parachain_code(id).is_none() {
+				return Err("registered parachain has no current code");
+			}
+			if <parachains::Module<T>>::parachain_head(id).is_none() {
+				return Err("registered parachain has no head");
+			}
+		}
+
+		<parachains::Module<T>>::do_try_state()
+	}
+
 	/// Ensures that the given `ParaId` corresponds to a registered parathread, and returns a descriptor if so.
 	pub fn ensure_thread_id(id: ParaId) -> Option<ParaInfo> {
 		Paras::get(id).and_then(|info| if let Scheduling::Dynamic = info.scheduling {
@@ -684,6 +942,7 @@ mod tests {
 		weights::DispatchInfo,
 	};
 	use keyring::Sr25519Keyring;
+	use std::cell::RefCell;
 
 	use crate::parachains;
 	use crate::slots;
@@ -822,6 +1081,7 @@ mod tests {
 		pub const ValidationUpgradeFrequency: BlockNumber = 10;
 		pub const ValidationUpgradeDelay: BlockNumber = 2;
 		pub const SlashPeriod: BlockNumber = 50;
+		pub const MaxForcedCodeUpdates: u32 = 32;
 		pub const ElectionLookahead: BlockNumber = 0;
 		pub const StakingUnsignedPriority: u64 = u64::max_value() / 2;
 	}
@@ -893,6 +1153,7 @@ mod tests {
 
 	impl parachains::Trait for Test {
 		type AuthorityId = test_keys::ReporterAuthorityId;
+		type Event = ();
 		type Origin = Origin;
 		type Call = Call;
 		type ParachainCurrency = balances::Module<Test>;
@@ -905,6 +1166,9 @@ mod tests {
 		type ValidationUpgradeFrequency = ValidationUpgradeFrequency;
 		type ValidationUpgradeDelay = ValidationUpgradeDelay;
 		type SlashPeriod = SlashPeriod;
+		type MaxForcedCodeUpdates = MaxForcedCodeUpdates;
+		type OnCodeUnavailable = ();
+		type OnBeforeCodePrune = ();
 		type Proof = sp_session::MembershipProof;
 		type KeyOwnerProofSystem = session::historical::Module<Test>;
 		type IdentificationTuple = <Self::KeyOwnerProofSystem as KeyOwnerProofSystem<(
@@ -939,6 +1203,13 @@ mod tests {
 		pub const ParathreadDeposit: Balance = 10;
 		pub const QueueSize: usize = 2;
 		pub const MaxRetries: u32 = 3;
+		pub DowngradePolicy: DowngradeUpgradePolicy = DOWNGRADE_POLICY.with(|v| *v.borrow());
+		pub const MaxBulkDeregistrations: u32 = 3;
+	}
+
+	thread_local! {
+		pub static DOWNGRADE_POLICY: RefCell<DowngradeUpgradePolicy> =
+			RefCell::new(DowngradeUpgradePolicy::Keep);
 	}
 
 	impl Trait for Test {
@@ -949,6 +1220,8 @@ mod tests {
 		type SwapAux = slots::Module<Test>;
 		type QueueSize = QueueSize;
 		type MaxRetries = MaxRetries;
+		type DowngradeUpgradePolicy = DowngradePolicy;
+		type MaxBulkDeregistrations = MaxBulkDeregistrations;
 	}
 
 	type Balances = balances::Module<Test>;
@@ -1319,6 +1592,359 @@ mod tests {
 		});
 	}
 
+	#[test]
+	fn parachains_paged_returns_contiguous_pages() {
+		let parachains = (1u32..=10)
+			.map(|i| (i.into(), vec![i as u8; 3].into(), vec![i as u8; 3].into()))
+			.collect();
+
+		new_test_ext(parachains).execute_with(|| {
+			let ids = |range: std::ops::RangeInclusive<u32>| -> Vec<ParaId> {
+				range.map(Into::into).collect()
+			};
+
+			assert_eq!(Registrar::parachains_paged(0, 4), ids(1..=4));
+			assert_eq!(Registrar::parachains_paged(4, 4), ids(5..=8));
+			assert_eq!(Registrar::parachains_paged(8, 4), ids(9..=10));
+			assert_eq!(Registrar::parachains_paged(0, 10), ids(1..=10));
+			assert!(Registrar::parachains_paged(10, 4).is_empty());
+			assert!(Registrar::parachains_paged(3, 0).is_empty());
+			assert_eq!(Registrar::parachains_paged(u32::max_value(), u32::max_value()), vec![]);
+		});
+	}
+
+	#[test]
+	fn parachains_stay_sorted_after_mutations() {
+		let parachains = vec![
+			(5u32.into(), vec![5; 3].into(), vec![5; 3].into()),
+			(3u32.into(), vec![3; 3].into(), vec![3; 3].into()),
+		];
+
+		new_test_ext(parachains).execute_with(|| {
+			run_to_block(2);
+
+			let register = |id: u32, scheduling| Registrar::register_para(
+				Origin::ROOT,
+				id.into(),
+				ParaInfo { scheduling },
+				vec![id as u8; 3].into(),
+				vec![id as u8; 3].into(),
+			);
+
+			assert_ok!(register(7, Scheduling::Always));
+			assert_ok!(register(1, Scheduling::Always));
+			assert_ok!(register(2, Scheduling::Dynamic));
+			assert_ok!(Registrar::deregister_para(Origin::ROOT, 5u32.into()));
+			assert_ok!(register(4, Scheduling::Always));
+			assert_ok!(Registrar::set_scheduling(Origin::ROOT, 2u32.into(), Scheduling::Always));
+			assert_ok!(Registrar::pause_para(Origin::ROOT, 1u32.into()));
+			assert_ok!(Registrar::set_scheduling(Origin::ROOT, 3u32.into(), Scheduling::Dynamic));
+			assert_ok!(Registrar::resume_para(
+				Origin::ROOT,
+				1u32.into(),
+				ParaInfo { scheduling: Scheduling::Always },
+			));
+
+			let expected: Vec<ParaId> = vec![1u32.into(), 2u32.into(), 4u32.into(), 7u32.into()];
+			assert_eq!(super::Parachains::get(), expected);
+
+			run_to_block(3);
+			assert_eq!(
+				Registrar::active_paras(),
+				expected.into_iter().map(|id| (id, None)).collect::<Vec<_>>(),
+			);
+		});
+	}
+
+	#[test]
+	fn paused_para_cannot_be_registered_anew() {
+		let parachains = vec![
+			(1u32.into(), vec![1; 3].into(), vec![1; 3].into()),
+		];
+
+		new_test_ext(parachains).execute_with(|| {
+			run_to_block(2);
+
+			let id = ParaId::from(1u32);
+			assert_ok!(Registrar::pause_para(Origin::ROOT, id));
+
+			assert_noop!(
+				Registrar::register_para(
+					Origin::ROOT,
+					id,
+					ParaInfo { scheduling: Scheduling::Always },
+					vec![2; 3].into(),
+					vec![2; 3].into(),
+				),
+				Error::<Test>::ParaPaused,
+			);
+
+			assert_ok!(Registrar::resume_para(
+				Origin::ROOT,
+				id,
+				ParaInfo { scheduling: Scheduling::Always },
+			));
+			assert_eq!(Parachains::parachain_code(&id), Some(vec![1; 3].into()));
+		});
+	}
+
+	#[test]
+	fn system_para_ids_are_reserved_for_root() {
+		new_test_ext(vec![]).execute_with(|| {
+			run_to_block(2);
+
+			assert!(Registrar::is_system_para(1u32.into()));
+			assert!(!Registrar::is_system_para(LOWEST_USER_ID));
+
+			// signed registration is given an ID outside of the reserved range.
+			assert_ok!(Registrar::register_parathread(
+				Origin::signed(3u64),
+				vec![3; 3].into(),
+				vec![3; 3].into(),
+			));
+			assert!(!Registrar::is_system_para(user_id(0)));
+			assert!(Registrar::paras(&user_id(0)).is_some());
+
+			// root may register within the reserved range.
+			assert_ok!(Registrar::register_para(
+				Origin::ROOT,
+				1u32.into(),
+				ParaInfo { scheduling: Scheduling::Always },
+				vec![1; 3].into(),
+				vec![1; 3].into(),
+			));
+			assert!(Registrar::paras(&ParaId::from(1u32)).is_some());
+		});
+	}
+
+	#[test]
+	fn register_para_with_request_id_is_idempotent() {
+		new_test_ext(vec![]).execute_with(|| {
+			run_to_block(2);
+
+			let register = |request_id: u64, id: u32| Registrar::register_para_with_request_id(
+				Origin::ROOT,
+				H256::from_low_u64_be(request_id),
+				id.into(),
+				ParaInfo { scheduling: Scheduling::Always },
+				vec![1; 3].into(),
+				vec![1; 3].into(),
+			);
+
+			assert_ok!(register(1, 2));
+			// a retried request is a no-op.
+			assert_ok!(register(1, 2));
+			assert_eq!(Registrar::paras(&ParaId::from(2u32)), Some(ParaInfo { scheduling: Scheduling::Always }));
+
+			// a distinct request for the same para is rejected.
+			assert_noop!(register(2, 2), Error::<Test>::ParaAlreadyExists);
+
+			run_to_block(3);
+			assert_eq!(Registrar::active_paras(), vec![(2u32.into(), None)]);
+		});
+	}
+
+	#[test]
+	fn seen_registration_requests_are_bounded() {
+		new_test_ext(vec![]).execute_with(|| {
+			run_to_block(2);
+
+			let register = |request_id: u64, id: u32| Registrar::register_para_with_request_id(
+				Origin::ROOT,
+				H256::from_low_u64_be(request_id),
+				id.into(),
+				ParaInfo { scheduling: Scheduling::Dynamic },
+				vec![1; 3].into(),
+				vec![1; 3].into(),
+			);
+
+			let max = MAX_SEEN_REGISTRATION_REQUESTS as u64;
+			for request_id in 0..=max {
+				assert_ok!(register(request_id, 1000 + request_id as u32));
+			}
+
+			let seen = <SeenRegistrationRequests<Test>>::get();
+			assert_eq!(seen.len(), MAX_SEEN_REGISTRATION_REQUESTS);
+			assert_eq!(seen.first(), Some(&H256::from_low_u64_be(1)));
+			assert_eq!(seen.last(), Some(&H256::from_low_u64_be(max)));
+
+			// the oldest request has been forgotten.
+			assert_noop!(register(0, 1000), Error::<Test>::ParaAlreadyExists);
+			assert_ok!(register(1, 1001));
+		});
+	}
+
+	#[test]
+	fn repair_parachains_order_sorts_and_dedups() {
+		new_test_ext(vec![]).execute_with(|| {
+			let ids = |ids: &[u32]| -> Vec<ParaId> { ids.iter().cloned().map(Into::into).collect() };
+
+			super::Parachains::put(ids(&[1, 2, 3]));
+			assert!(!Registrar::repair_parachains_order());
+			assert_eq!(super::Parachains::get(), ids(&[1, 2, 3]));
+
+			super::Parachains::put(ids(&[5, 3, 5, 1, 3]));
+			assert!(Registrar::repair_parachains_order());
+			assert_eq!(super::Parachains::get(), ids(&[1, 3, 5]));
+		});
+	}
+
+	#[test]
+	fn do_try_state_catches_inconsistent_parachains() {
+		let parachains = vec![
+			(1u32.into(), vec![1; 3].into(), vec![1; 3].into()),
+			(2u32.into(), vec![2; 3].into(), vec![2; 3].into()),
+		];
+
+		new_test_ext(parachains).execute_with(|| {
+			let (id_a, id_b) = (ParaId::from(1u32), ParaId::from(2u32));
+			assert_eq!(Registrar::do_try_state(), Ok(()));
+
+			super::Parachains::put(vec![id_b, id_a]);
+			assert_eq!(Registrar::do_try_state(), Err("`Parachains` is not sorted and deduplicated"));
+
+			super::Parachains::put(vec![id_a, id_a, id_b]);
+			assert_eq!(Registrar::do_try_state(), Err("`Parachains` is not sorted and deduplicated"));
+
+			super::Parachains::put(vec![id_a, id_b]);
+			let code = parachains::Code::take(&id_b).unwrap();
+			assert_eq!(Registrar::do_try_state(), Err("registered parachain has no current code"));
+			parachains::Code::insert(&id_b, code);
+
+			let head = parachains::Heads::take(&id_b).unwrap();
+			assert_eq!(Registrar::do_try_state(), Err("registered parachain has no head"));
+			parachains::Heads::insert(&id_b, head);
+
+			assert_eq!(Registrar::do_try_state(), Ok(()));
+		});
+	}
+
+	#[test]
+	fn deregister_paras_skips_unknown_paras() {
+		let parachains = vec![
+			(1u32.into(), vec![1; 3].into(), vec![1; 3].into()),
+			(2u32.into(), vec![2; 3].into(), vec![2; 3].into()),
+			(3u32.into(), vec![3; 3].into(), vec![3; 3].into()),
+		];
+
+		new_test_ext(parachains).execute_with(|| {
+			let ids = |ids: &[u32]| -> Vec<ParaId> { ids.iter().cloned().map(Into::into).collect() };
+
+			assert_noop!(
+				Registrar::deregister_paras(Origin::ROOT, ids(&[1, 2, 3, 4])),
+				Error::<Test>::TooManyParas,
+			);
+
+			assert_eq!(Registrar::do_deregister_paras(ids(&[1, 4, 3])), (ids(&[1, 3]), ids(&[4])));
+
+			assert_eq!(super::Parachains::get(), ids(&[2]));
+			assert!(Registrar::paras(&ParaId::from(1u32)).is_none());
+			assert!(Registrar::paras(&ParaId::from(3u32)).is_none());
+			assert!(Parachains::parachain_code(&ParaId::from(1u32)).is_none());
+			assert!(Parachains::parachain_code(&ParaId::from(3u32)).is_none());
+
+			assert_ok!(Registrar::deregister_paras(Origin::ROOT, ids(&[2, 3])));
+			assert!(super::Parachains::get().is_empty());
+		});
+	}
+
+	#[test]
+	fn set_scheduling_changes_para_kind() {
+		new_test_ext(vec![]).execute_with(|| {
+			run_to_block(2);
+
+			assert_ok!(Registrar::register_para(
+				Origin::ROOT,
+				2u32.into(),
+				ParaInfo { scheduling: Scheduling::Dynamic },
+				vec![2; 3].into(),
+				vec![2; 3].into(),
+			));
+
+			run_to_block(3);
+			assert_eq!(Registrar::active_paras(), vec![]);
+
+			assert_ok!(Registrar::set_scheduling(Origin::ROOT, 2u32.into(), Scheduling::Always));
+			assert_eq!(
+				Registrar::paras(&ParaId::from(2u32)),
+				Some(ParaInfo { scheduling: Scheduling::Always })
+			);
+
+			run_to_block(4);
+			assert_eq!(Registrar::active_paras(), vec![(2u32.into(), None)]);
+
+			assert_ok!(Registrar::set_scheduling(Origin::ROOT, 2u32.into(), Scheduling::Dynamic));
+
+			run_to_block(5);
+			assert_eq!(Registrar::active_paras(), vec![]);
+			assert_eq!(
+				Registrar::paras(&ParaId::from(2u32)),
+				Some(ParaInfo { scheduling: Scheduling::Dynamic })
+			);
+
+			assert_noop!(
+				Registrar::set_scheduling(Origin::ROOT, 3u32.into(), Scheduling::Always),
+				Error::<Test>::InvalidChainId,
+			);
+		});
+	}
+
+	fn register_para_with_planned_upgrade(id: ParaId) {
+		assert_ok!(Registrar::register_para(
+			Origin::ROOT,
+			id,
+			ParaInfo { scheduling: Scheduling::Always },
+			vec![1; 3].into(),
+			vec![1; 3].into(),
+		));
+		assert_ok!(Parachains::force_schedule_code_upgrade(Origin::ROOT, id, vec![2; 3].into(), 0));
+		assert!(Parachains::code_upgrade_schedule(&id).is_some());
+	}
+
+	#[test]
+	fn downgrade_keeps_planned_upgrade_under_keep_policy() {
+		new_test_ext(vec![]).execute_with(|| {
+			run_to_block(2);
+
+			let id = ParaId::from(2u32);
+			register_para_with_planned_upgrade(id);
+
+			assert_ok!(Registrar::set_scheduling(Origin::ROOT, id, Scheduling::Dynamic));
+
+			let expected_at = 2 + <Test as parachains::Trait>::ValidationUpgradeDelay::get();
+			assert_eq!(Parachains::code_upgrade_schedule(&id), Some(expected_at));
+			assert_eq!(
+				Parachains::export_para_code(&id).future,
+				Some((expected_at, vec![2; 3].into())),
+			);
+
+			// the upgrade is applied by the first head of the parathread which is due.
+			run_to_block(expected_at);
+			assert_ok!(Parachains::force_note_new_head_at(Origin::ROOT, id, vec![3; 3].into(), expected_at));
+			assert_eq!(Parachains::parachain_code(&id), Some(vec![2; 3].into()));
+			assert_eq!(Parachains::parachain_code_at(&id, expected_at), Some(vec![1; 3].into()));
+			assert!(Parachains::code_upgrade_schedule(&id).is_none());
+		});
+	}
+
+	#[test]
+	fn downgrade_discards_planned_upgrade_under_discard_policy() {
+		DOWNGRADE_POLICY.with(|v| *v.borrow_mut() = DowngradeUpgradePolicy::Discard);
+
+		new_test_ext(vec![]).execute_with(|| {
+			run_to_block(2);
+
+			let id = ParaId::from(2u32);
+			register_para_with_planned_upgrade(id);
+
+			assert_ok!(Registrar::set_scheduling(Origin::ROOT, id, Scheduling::Dynamic));
+
+			assert!(Parachains::code_upgrade_schedule(&id).is_none());
+			assert!(Parachains::export_para_code(&id).future.is_none());
+			assert_eq!(Parachains::parachain_code(&id), Some(vec![1; 3].into()));
+		});
+	}
+
 	#[test]
 	fn parathread_scheduling_works() {
 		new_test_ext(vec![]).execute_with(|| {
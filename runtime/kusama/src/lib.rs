@@ -538,10 +538,12 @@ parameter_types! {
 	pub const ValidationUpgradeFrequency: BlockNumber = 2 * DAYS;
 	pub const ValidationUpgradeDelay: BlockNumber = 8 * HOURS;
 	pub const SlashPeriod: BlockNumber = 7 * DAYS;
+	pub const MaxForcedCodeUpdates: u32 = 32;
 }
 
 impl parachains::Trait for Runtime {
 	type AuthorityId = primitives::fisherman::FishermanAppCrypto;
+	type Event = Event;
 	type Origin = Origin;
 	type Call = Call;
 	type ParachainCurrency = Balances;
@@ -555,6 +557,9 @@ impl parachains::Trait for Runtime {
 	type ValidationUpgradeFrequency = ValidationUpgradeFrequency;
 	type ValidationUpgradeDelay = ValidationUpgradeDelay;
 	type SlashPeriod = SlashPeriod;
+	type MaxForcedCodeUpdates = MaxForcedCodeUpdates;
+	type OnCodeUnavailable = ();
+	type OnBeforeCodePrune = ();
 
 	type Proof = sp_session::MembershipProof;
 	type KeyOwnerProofSystem = session::historical::Module<Self>;
@@ -626,6 +631,9 @@ parameter_types! {
 	pub const ParathreadDeposit: Balance = 5 * DOLLARS;
 	pub const QueueSize: usize = 2;
 	pub const MaxRetries: u32 = 3;
+	pub const DowngradePolicy: registrar::DowngradeUpgradePolicy =
+		registrar::DowngradeUpgradePolicy::Keep;
+	pub const MaxBulkDeregistrations: u32 = 32;
 }
 
 impl registrar::Trait for Runtime {
@@ -636,6 +644,8 @@ impl registrar::Trait for Runtime {
 	type SwapAux = Slots;
 	type QueueSize = QueueSize;
 	type MaxRetries = MaxRetries;
+	type DowngradeUpgradePolicy = DowngradePolicy;
+	type MaxBulkDeregistrations = MaxBulkDeregistrations;
 }
 
 parameter_types! {
@@ -802,7 +812,7 @@ construct_runtime! {
 
 		// Parachains stuff; slots are disabled (no auctions initially). The rest are safe as they
 		// have no public dispatchables.
-		Parachains: parachains::{Module, Call, Storage, Config, Inherent, Origin},
+		Parachains: parachains::{Module, Call, Storage, Config, Inherent, Origin, Event<T>},
 		Attestations: attestations::{Module, Call, Storage},
 		Slots: slots::{Module, Call, Storage, Event<T>},
 		Registrar: registrar::{Module, Call, Storage, Event, Config<T>},
@@ -562,10 +562,12 @@ parameter_types! {
 	pub const ValidationUpgradeFrequency: BlockNumber = 7 * DAYS;
 	pub const ValidationUpgradeDelay: BlockNumber = 1 * DAYS;
 	pub const SlashPeriod: BlockNumber = 28 * DAYS;
+	pub const MaxForcedCodeUpdates: u32 = 32;
 }
 
 impl parachains::Trait for Runtime {
 	type AuthorityId = primitives::fisherman::FishermanAppCrypto;
+	type Event = Event;
 	type Origin = Origin;
 	type Call = Call;
 	type ParachainCurrency = Balances;
@@ -579,6 +581,9 @@ impl parachains::Trait for Runtime {
 	type ValidationUpgradeFrequency = ValidationUpgradeFrequency;
 	type ValidationUpgradeDelay = ValidationUpgradeDelay;
 	type SlashPeriod = SlashPeriod;
+	type MaxForcedCodeUpdates = MaxForcedCodeUpdates;
+	type OnCodeUnavailable = ();
+	type OnBeforeCodePrune = ();
 
 	type Proof = sp_session::MembershipProof;
 	type KeyOwnerProofSystem = session::historical::Module<Self>;
@@ -651,6 +656,9 @@ parameter_types! {
 	pub const ParathreadDeposit: Balance = 500 * DOLLARS;
 	pub const QueueSize: usize = 2;
 	pub const MaxRetries: u32 = 3;
+	pub const DowngradePolicy: registrar::DowngradeUpgradePolicy =
+		registrar::DowngradeUpgradePolicy::Keep;
+	pub const MaxBulkDeregistrations: u32 = 32;
 }
 
 impl registrar::Trait for Runtime {
@@ -661,6 +669,8 @@ impl registrar::Trait for Runtime {
 	type SwapAux = Slots;
 	type QueueSize = QueueSize;
 	type MaxRetries = MaxRetries;
+	type DowngradeUpgradePolicy = DowngradePolicy;
+	type MaxBulkDeregistrations = MaxBulkDeregistrations;
 }
 
 parameter_types! {
@@ -763,7 +773,7 @@ construct_runtime! {
 		// Parachains stuff; slots are disabled (no auctions initially). The rest are safe as they
 		// have no public dispatchables. Disabled `Call` on all of them, but this should be
 		// uncommented once we're ready to start parachains.
-		Parachains: parachains::{Module, Call, Storage, Config, Inherent, Origin},
+		Parachains: parachains::{Module, Call, Storage, Config, Inherent, Origin, Event<T>},
 		Attestations: attestations::{Module, Call, Storage},
 		Slots: slots::{Module, Call, Storage, Event<T>},
 		Registrar: registrar::{Module, Call, Storage, Event, Config<T>},
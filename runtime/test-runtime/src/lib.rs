@@ -348,10 +348,12 @@ parameter_types! {
 	pub const ValidationUpgradeFrequency: BlockNumber = 2;
 	pub const ValidationUpgradeDelay: BlockNumber = 1;
 	pub const SlashPeriod: BlockNumber = 1 * MINUTES;
+	pub const MaxForcedCodeUpdates: u32 = 32;
 }
 
 impl parachains::Trait for Runtime {
 	type AuthorityId = primitives::fisherman::FishermanAppCrypto;
+	type Event = Event;
 	type Origin = Origin;
 	type Call = Call;
 	type ParachainCurrency = Balances;
@@ -365,6 +367,9 @@ impl parachains::Trait for Runtime {
 	type ValidationUpgradeFrequency = ValidationUpgradeFrequency;
 	type ValidationUpgradeDelay = ValidationUpgradeDelay;
 	type SlashPeriod = SlashPeriod;
+	type MaxForcedCodeUpdates = MaxForcedCodeUpdates;
+	type OnCodeUnavailable = ();
+	type OnBeforeCodePrune = ();
 
 	type Proof = sp_session::MembershipProof;
 	type KeyOwnerProofSystem = session::historical::Module<Self>;
@@ -437,6 +442,9 @@ parameter_types! {
 	pub const ParathreadDeposit: Balance = 5 * DOLLARS;
 	pub const QueueSize: usize = 2;
 	pub const MaxRetries: u32 = 3;
+	pub const DowngradePolicy: registrar::DowngradeUpgradePolicy =
+		registrar::DowngradeUpgradePolicy::Keep;
+	pub const MaxBulkDeregistrations: u32 = 32;
 }
 
 impl registrar::Trait for Runtime {
@@ -447,6 +455,8 @@ impl registrar::Trait for Runtime {
 	type SwapAux = Slots;
 	type QueueSize = QueueSize;
 	type MaxRetries = MaxRetries;
+	type DowngradeUpgradePolicy = DowngradePolicy;
+	type MaxBulkDeregistrations = MaxBulkDeregistrations;
 }
 
 parameter_types! {
@@ -515,7 +525,7 @@ construct_runtime! {
 
 		// Parachains stuff; slots are disabled (no auctions initially). The rest are safe as they
 		// have no public dispatchables.
-		Parachains: parachains::{Module, Call, Storage, Config, Inherent, Origin},
+		Parachains: parachains::{Module, Call, Storage, Config, Inherent, Origin, Event<T>},
 		Attestations: attestations::{Module, Call, Storage},
 		Slots: slots::{Module, Call, Storage, Event<T>},
 		Registrar: registrar::{Module, Call, Storage, Event, Config<T>},